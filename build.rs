@@ -1,5 +1,11 @@
+use std::env;
+use std::path::PathBuf;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("api/proto/registry.proto")?;
+    // The descriptor set is embedded in reg-server for gRPC server reflection.
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("registry_descriptor.bin"))
+        .compile_protos(&["api/proto/registry.proto"], &["api/proto"])?;
     Ok(())
-}
\ No newline at end of file
+}
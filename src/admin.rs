@@ -0,0 +1,313 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Admin functions for operators. An admin token is issued in exchange for
+// admin_secret from setting.toml and lets the holder remove any instance,
+// remove a whole protobuf name or clear the client token of a protobuf, so
+// entries whose provider lost its token do not linger until a restart. It
+// also cordons instances or protobuf names, keeping them out of FIND without
+// deregistering them. Without admin_secret the admin functions are disabled.
+// An admin token is good in every namespace unless it was asked for one.
+
+use jwt_simple::prelude::Duration;
+use crate::{audit, common, metrics, Protobufs, Service, GDATA};
+use crate::common::make_status_packet;
+use crate::registry::{AdminAuthRequest, AdminResponse, AuthorizeResponse, ClearClientTokenRequest,
+                      CordonRequest, RemoveInstanceRequest, RemoveProtobufRequest, StatusPacket};
+
+// Compare the secrets without stopping at the first difference.
+pub fn secret_matches(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().min(b.len()) {
+        diff |= (a[i] ^ b[i]) as usize;
+    }
+    diff == 0
+}
+
+// Issue an admin token when the secret matches admin_secret.
+pub fn handle_admin_auth(req: AdminAuthRequest) -> AuthorizeResponse {
+    let secret = match common::setting("admin_secret") {
+        Some(s) if !s.is_empty() => s,
+        _ => return admin_auth_response(None, Some(disabled())),
+    };
+    if !secret_matches(req.secret.as_bytes(), secret.as_bytes()) {
+        tracing::warn!("admin secret rejected");
+        audit::record(audit::AuditEvent {
+            event: "token_rejected",
+            method: "adminauth".to_string(),
+            reason: "admin secret mismatch".to_string(),
+            ..Default::default()
+        });
+        let s = make_status_packet(common::StatusEnum::AUTHERROR, "invalid admin secret".to_string());
+        return admin_auth_response(None, Some(s));
+    }
+    let ns = match req.namespace.is_empty() {
+        true => common::ALL_NAMESPACES.to_string(),
+        false => match common::check_namespace(&req.namespace) {
+            Ok(ns) => ns,
+            Err(e) => {
+                let s = make_status_packet(common::StatusEnum::AUTHERROR, e);
+                return admin_auth_response(None, Some(s));
+            },
+        },
+    };
+    match common::make_token("admin".to_string(), "admin".to_string(), "".to_string(), ns.clone(), true,
+                             Duration::from_hours(1)) {
+        Some(token) => {
+            audit::record(audit::AuditEvent {
+                event: "token_issued",
+                namespace: ns,
+                user: "admin".to_string(),
+                token_id: audit::token_id(&token),
+                ..Default::default()
+            });
+            admin_auth_response(Some(token), None)
+        },
+        None => {
+            let s = make_status_packet(common::StatusEnum::BADTOKEN, "failed to create jwt token".to_string());
+            admin_auth_response(None, Some(s))
+        },
+    }
+}
+
+fn admin_auth_response(token: Option<String>, status: Option<StatusPacket>) -> AuthorizeResponse {
+    AuthorizeResponse {
+        token: token.unwrap_or_default(),
+        status,
+    }
+}
+
+fn disabled() -> StatusPacket {
+    make_status_packet(common::StatusEnum::AUTHERROR, "admin access is not configured".to_string())
+}
+
+// Check that the token is a valid admin token for the namespace.
+fn check_admin(method: &str, token: String, namespace: &str) -> Result<(), StatusPacket> {
+    if common::setting("admin_secret").is_none_or(|s| s.is_empty()) {
+        return Err(disabled());
+    }
+    match common::check_scoped_token(method, token, namespace) {
+        Ok(c) if c.custom.user_is_admin => Ok(()),
+        Ok(_) => Err(make_status_packet(common::StatusEnum::BADTOKEN, "token is not an admin token".to_string())),
+        Err(e) => Err(make_status_packet(common::StatusEnum::AUTHERROR, e)),
+    }
+}
+
+// Remove one instance, by id or else by url. The protobufs structure must
+// already be locked. A protobuf left without services is removed as well,
+// unless it holds schemas. Returns the removed instance.
+pub fn remove_instance(protobufs: &mut Protobufs, namespace: &str, protobuf_name: &str, id: &str, url: &str)
+    -> Result<Service, String> {
+    let (svc, empty) = match common::find_protobuf(protobufs, namespace, protobuf_name.to_string()) {
+        Some(m) => {
+            let mut protobuf = m.lock().unwrap();
+            let idx = common::find_instance(&protobuf, id, url)?;
+            let svc = protobuf.services.remove(idx).into_inner().unwrap();
+            (svc, protobuf.services.is_empty())
+        },
+        None => return Err("protobuf does not exist".to_string()),
+    };
+    if empty {
+        common::release_protobuf_group(protobufs, namespace, protobuf_name);
+    }
+    Ok(svc)
+}
+
+// Remove a protobuf name with its instances and schemas. Returns the number
+// of instances removed.
+pub fn remove_protobuf(protobufs: &mut Protobufs, namespace: &str, protobuf_name: &str) -> Result<i32, String> {
+    match common::remove_protobuf_group(protobufs, namespace, protobuf_name) {
+        Some(m) => Ok(m.into_inner().unwrap().services.len() as i32),
+        None => Err("protobuf does not exist".to_string()),
+    }
+}
+
+pub fn handle_remove_instance(req: RemoveInstanceRequest) -> AdminResponse {
+    if let Err(s) = check_admin("evict", req.token, &req.namespace) {
+        return admin_response(0, Some(s));
+    }
+    let ns = common::namespace_name(&req.namespace);
+    let mut protobufs = GDATA.get().unwrap().lock().unwrap();
+    match remove_instance(&mut protobufs, &ns, &req.protobuf_name, &req.instance_id, &req.service_url) {
+        Ok(svc) => {
+            metrics::eviction("admin");
+            tracing::info!(namespace = %ns, protobuf_name = %req.protobuf_name, url = %svc.url,
+                           instance_id = %svc.id, "admin removed instance");
+            audit::record(audit::AuditEvent {
+                event: "admin_evict",
+                namespace: ns,
+                protobuf_name: req.protobuf_name,
+                url: svc.url,
+                instance_id: svc.id,
+                user: "admin".to_string(),
+                ..Default::default()
+            });
+            admin_response(1, None)
+        },
+        Err(e) => admin_response(0, Some(make_status_packet(common::StatusEnum::NOTFOUND, e))),
+    }
+}
+
+pub fn handle_remove_protobuf(req: RemoveProtobufRequest) -> AdminResponse {
+    if let Err(s) = check_admin("purge", req.token, &req.namespace) {
+        return admin_response(0, Some(s));
+    }
+    let ns = common::namespace_name(&req.namespace);
+    let mut protobufs = GDATA.get().unwrap().lock().unwrap();
+    match remove_protobuf(&mut protobufs, &ns, &req.protobuf_name) {
+        Ok(removed) => {
+            for _ in 0..removed {
+                metrics::eviction("admin");
+            }
+            tracing::info!(namespace = %ns, protobuf_name = %req.protobuf_name, removed, "admin removed protobuf");
+            audit::record(audit::AuditEvent {
+                event: "admin_purge",
+                namespace: ns,
+                protobuf_name: req.protobuf_name,
+                user: "admin".to_string(),
+                reason: format!("{} instances removed", removed),
+                ..Default::default()
+            });
+            admin_response(removed, None)
+        },
+        Err(e) => admin_response(0, Some(make_status_packet(common::StatusEnum::NOTFOUND, e))),
+    }
+}
+
+pub fn handle_clear_client_token(req: ClearClientTokenRequest) -> AdminResponse {
+    if let Err(s) = check_admin("revoke", req.token, &req.namespace) {
+        return admin_response(0, Some(s));
+    }
+    let ns = common::namespace_name(&req.namespace);
+    let protobufs = GDATA.get().unwrap().lock().unwrap();
+    match common::find_protobuf(&protobufs, &ns, req.protobuf_name.clone()) {
+        Some(p) => {
+            p.lock().unwrap().cltk = None;
+            tracing::info!(namespace = %ns, protobuf_name = %req.protobuf_name, "admin cleared client token");
+            audit::record(audit::AuditEvent {
+                event: "admin_revoke",
+                namespace: ns,
+                protobuf_name: req.protobuf_name,
+                user: "admin".to_string(),
+                ..Default::default()
+            });
+            admin_response(0, None)
+        },
+        None => {
+            let s = make_status_packet(common::StatusEnum::NOTFOUND, "protobuf does not exist".to_string());
+            admin_response(0, Some(s))
+        },
+    }
+}
+
+// Cordon or uncordon one instance, by id or else by url, or the whole
+// protobuf name when neither is given. The protobufs structure must already
+// be locked.
+pub fn set_cordon(protobufs: &Protobufs, namespace: &str, protobuf_name: &str, id: &str, url: &str,
+                  cordon: bool) -> Result<(), String> {
+    let mut protobuf = match common::find_protobuf(protobufs, namespace, protobuf_name.to_string()) {
+        Some(m) => m.lock().unwrap(),
+        None => return Err("protobuf does not exist".to_string()),
+    };
+    if id.is_empty() && url.is_empty() {
+        protobuf.cdn = cordon;
+        return Ok(());
+    }
+    let idx = common::find_instance(&protobuf, id, url)?;
+    protobuf.services[idx].lock().unwrap().cdn = cordon;
+    Ok(())
+}
+
+pub fn handle_cordon(req: CordonRequest) -> AdminResponse {
+    if let Err(s) = check_admin("cordon", req.token, &req.namespace) {
+        return admin_response(0, Some(s));
+    }
+    let ns = common::namespace_name(&req.namespace);
+    let protobufs = GDATA.get().unwrap().lock().unwrap();
+    match set_cordon(&protobufs, &ns, &req.protobuf_name, &req.instance_id, &req.service_url, !req.uncordon) {
+        Ok(_) => {
+            let event = if req.uncordon { "admin_uncordon" } else { "admin_cordon" };
+            tracing::info!(namespace = %ns, protobuf_name = %req.protobuf_name, url = %req.service_url,
+                           instance_id = %req.instance_id, event, "admin changed cordon");
+            audit::record(audit::AuditEvent {
+                event,
+                namespace: ns,
+                protobuf_name: req.protobuf_name,
+                url: req.service_url,
+                instance_id: req.instance_id,
+                user: "admin".to_string(),
+                ..Default::default()
+            });
+            admin_response(0, None)
+        },
+        Err(e) => admin_response(0, Some(make_status_packet(common::StatusEnum::NOTFOUND, e))),
+    }
+}
+
+fn admin_response(removed: i32, status: Option<StatusPacket>) -> AdminResponse {
+    AdminResponse {
+        status,
+        removed,
+    }
+}
+
+#[test]
+fn test_admin_removals() {
+    let mut protobufs = Protobufs {
+        namespaces: std::collections::HashMap::new(),
+    };
+    for name in ["proto1", "proto2"] {
+        common::add_protobuf(&mut protobufs, "", name.to_string()).unwrap();
+        let mut p = common::find_protobuf(&protobufs, "", name.to_string()).unwrap().lock().unwrap();
+        common::add_service(&mut p, "url1".to_string(), None).unwrap();
+        common::add_service(&mut p, "url2".to_string(), None).unwrap();
+    }
+    assert!(remove_instance(&mut protobufs, "", "proto1", "", "url3").is_err());
+    assert!(remove_instance(&mut protobufs, "", "proto3", "", "url1").is_err());
+    assert_eq!(remove_instance(&mut protobufs, "", "proto1", "", "url1").unwrap().url, "url1");
+    assert!(common::find_protobuf(&protobufs, "", "proto1".to_string()).is_some());
+    let id = common::find_protobuf(&protobufs, "", "proto1".to_string()).unwrap().lock().unwrap()
+        .services[0].lock().unwrap().id.clone();
+    assert_eq!(remove_instance(&mut protobufs, "", "proto1", &id, "").unwrap().url, "url2");
+    assert!(common::find_protobuf(&protobufs, "", "proto1".to_string()).is_none(), "empty protobuf not removed");
+    assert_eq!(remove_protobuf(&mut protobufs, "", "proto2"), Ok(2));
+    assert!(remove_protobuf(&mut protobufs, "", "proto2").is_err());
+    // names in another namespace are out of reach
+    common::add_protobuf(&mut protobufs, "dev", "proto2".to_string()).unwrap();
+    assert!(remove_protobuf(&mut protobufs, "", "proto2").is_err());
+    assert_eq!(remove_protobuf(&mut protobufs, "dev", "proto2"), Ok(0));
+
+    common::add_protobuf(&mut protobufs, "", "proto3".to_string()).unwrap();
+    {
+        let mut p = common::find_protobuf(&protobufs, "", "proto3".to_string()).unwrap().lock().unwrap();
+        common::add_service(&mut p, "url1".to_string(), None).unwrap();
+    }
+    set_cordon(&protobufs, "", "proto3", "", "url1", true).unwrap();
+    set_cordon(&protobufs, "", "proto3", "", "", true).unwrap();
+    assert!(set_cordon(&protobufs, "", "proto3", "", "url2", true).is_err());
+    {
+        let p = common::find_protobuf(&protobufs, "", "proto3".to_string()).unwrap().lock().unwrap();
+        assert!(p.cdn && p.services[0].lock().unwrap().cdn);
+    }
+    set_cordon(&protobufs, "", "proto3", "", "url1", false).unwrap();
+    assert!(!common::find_protobuf(&protobufs, "", "proto3".to_string()).unwrap().lock().unwrap()
+        .services[0].lock().unwrap().cdn);
+
+    assert!(secret_matches(b"s3cret", b"s3cret"));
+    assert!(!secret_matches(b"s3cre", b"s3cret"));
+    assert!(!secret_matches(b"s3creT", b"s3cret"));
+}
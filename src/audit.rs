@@ -0,0 +1,219 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Audit trail of security relevant events: registrations, deregistrations,
+// evictions, admin removals, tokens issued and tokens rejected. Events are
+// appended as JSON lines to audit_file from setting.toml, or to stderr when it
+// is "stderr". Stdout carries the logs, so it is refused.
+// The file is rotated once it reaches audit_max_bytes, keeping audit_max_files
+// rotated files. Tokens are identified by a hash, never written out.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use crate::balancer;
+
+static AUDIT: OnceCell<Mutex<AuditLog>> = OnceCell::new();
+
+thread_local! {
+    // Address of the caller whose RPC is being handled on this thread.
+    static PEER: RefCell<String> = const { RefCell::new(String::new()) };
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct AuditEvent {
+    pub time: u64,              // unix time in milliseconds
+    pub event: &'static str,    // register, deregister, evict, token_issued, token_rejected, admin_*
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub peer: String,           // caller address
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub namespace: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub protobuf_name: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub instance_id: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub user: String,           // token user, the service url, "client" or "admin"
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub token_id: String,       // hash identifying the token
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub method: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+}
+
+enum Sink {
+    Stderr,
+    File(File),
+}
+
+pub struct AuditLog {
+    path: String,
+    sink: Sink,
+    size: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl AuditLog {
+    pub fn open(path: &str, max_bytes: u64, max_files: u32) -> Result<AuditLog, String> {
+        if path == "stdout" {
+            return Err("audit_file cannot be stdout, the logs are written there, use stderr or a file".to_string());
+        }
+        if path == "stderr" {
+            return Ok(AuditLog { path: path.to_string(), sink: Sink::Stderr, size: 0, max_bytes, max_files });
+        }
+        let file = open_append(path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(AuditLog { path: path.to_string(), sink: Sink::File(file), size, max_bytes, max_files })
+    }
+
+    // Append one event as a line, rotating the file first when it is full.
+    pub fn write(&mut self, event: &AuditEvent) -> Result<(), String> {
+        let mut line = match serde_json::to_string(event) {
+            Ok(v) => v,
+            Err(e) => return Err(format!("failed to serialize audit event: {}", e)),
+        };
+        line.push('\n');
+        if let Sink::Stderr = self.sink {
+            // one write under the lock so lines never mix with other output
+            return std::io::stderr().lock().write_all(line.as_bytes())
+                .map_err(|e| format!("failed to write audit event to stderr: {}", e));
+        }
+        if self.max_bytes > 0 && self.size > 0 && self.size + line.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        if let Sink::File(file) = &mut self.sink
+            && let Err(e) = file.write_all(line.as_bytes()) {
+            return Err(format!("failed to write to file '{}': {}", self.path, e));
+        }
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // Shift audit.log.1 to audit.log.2 and so on, dropping the oldest, then
+    // move the current file to audit.log.1 and start a new one.
+    fn rotate(&mut self) -> Result<(), String> {
+        if self.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(format!("{}.{}", self.path, n), format!("{}.{}", self.path, n + 1));
+            }
+            if let Err(e) = fs::rename(&self.path, format!("{}.1", self.path)) {
+                return Err(format!("failed to rotate '{}': {}", self.path, e));
+            }
+        }
+        self.sink = Sink::File(open_append(&self.path)?);
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &str) -> Result<File, String> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(f) => Ok(f),
+        Err(e) => Err(format!("failed to open file '{}': {}", path, e)),
+    }
+}
+
+// Start the audit log when audit_file is configured.
+pub fn init(params: &HashMap<String, String>) -> Result<(), String> {
+    let path = match params.get("audit_file") {
+        Some(p) => p,
+        None => return Ok(()),
+    };
+    let max_bytes = crate::config_number(params, "audit_max_bytes", 10 * 1024 * 1024);
+    let max_files = crate::config_number(params, "audit_max_files", 5) as u32;
+    let log = AuditLog::open(path, max_bytes, max_files)?;
+    let _ = AUDIT.set(Mutex::new(log));
+    Ok(())
+}
+
+// Run a handler with the caller's address available to the events it records.
+pub fn with_peer<T>(peer: Option<SocketAddr>, f: impl FnOnce() -> T) -> T {
+    let addr = peer.map(|p| p.to_string()).unwrap_or_default();
+    PEER.with(|p| *p.borrow_mut() = addr);
+    let r = f();
+    PEER.with(|p| p.borrow_mut().clear());
+    r
+}
+
+// Short identifier of a token for correlating events without storing it.
+pub fn token_id(token: &str) -> String {
+    format!("{:016x}", balancer::fnv1a(token.as_bytes()))
+}
+
+// Record an event. Does nothing when the audit log is not configured, or
+// for a cluster write another node took and audited.
+pub fn record(mut event: AuditEvent) {
+    let log = match AUDIT.get() {
+        Some(l) if !crate::common::replica() => l,
+        _ => return,
+    };
+    event.time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0);
+    if event.peer.is_empty() {
+        event.peer = PEER.with(|p| p.borrow().clone());
+    }
+    if let Err(e) = log.lock().unwrap().write(&event) {
+        tracing::error!(error = %e, "failed to write audit event");
+    }
+}
+
+#[test]
+fn test_audit_log_refuses_stdout() {
+    assert!(AuditLog::open("stdout", 0, 0).is_err(), "audit lines would mix with the logs");
+    assert!(AuditLog::open("stderr", 0, 0).is_ok());
+}
+
+#[test]
+fn test_audit_log_rotation() {
+    let dir = std::env::temp_dir().join(format!("registry-audit-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+    let path = path.to_str().unwrap();
+
+    let mut log = AuditLog::open(path, 200, 2).unwrap();
+    for i in 0..10 {
+        let event = AuditEvent {
+            event: "register",
+            protobuf_name: "proto1".to_string(),
+            url: format!("localhost:{}", 8000 + i),
+            ..Default::default()
+        };
+        log.write(&event).unwrap();
+    }
+    let current = fs::read_to_string(path).unwrap();
+    let line: serde_json::Value = serde_json::from_str(current.lines().last().unwrap()).unwrap();
+    assert_eq!(line["event"], "register");
+    assert_eq!(line["url"], "localhost:8009");
+    assert!(line.get("reason").is_none(), "empty fields are written");
+    assert!(fs::metadata(format!("{}.1", path)).is_ok());
+    assert!(fs::metadata(format!("{}.2", path)).is_ok());
+    assert!(fs::metadata(format!("{}.3", path)).is_err(), "too many rotated files kept");
+    assert!(fs::metadata(path).unwrap().len() <= 200);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -15,17 +15,18 @@
  *
  */
 use jwt_simple::prelude::Duration;
-use crate::{common, registry, GDATA};
+use crate::{audit, common, registry, GDATA};
 use crate::registry::{AuthorizeResponse};
 
 // Authorize is used by clients to obtain a JWT allowing FIND requests against a specific
-// protobuf service.
-pub fn handle_authorize(protobuf_name: String) -> AuthorizeResponse {
+// protobuf service. The token is only good in the namespace of the protobuf.
+pub fn handle_authorize(namespace: &str, protobuf_name: String) -> AuthorizeResponse {
 
     let protobufs = GDATA.get().unwrap().lock().unwrap();
     // lookup protobuf and error if not found
     let name = protobuf_name.clone();
-    let protodef = common::find_protobuf(&protobufs, protobuf_name.clone());
+    let ns = common::namespace_name(namespace);
+    let protodef = common::find_protobuf(&protobufs, &ns, protobuf_name.clone());
     if protodef.is_none() {
         let s = common::make_status_packet(common::StatusEnum::NOTFOUND,
                                            "no matching protobuf definition".to_string());
@@ -37,12 +38,20 @@ pub fn handle_authorize(protobuf_name: String) -> AuthorizeResponse {
     }
 
     // Utility routine to generate a jwt token for client functions
-    let token = common::make_token("client".to_string(), name.to_string(), false,
-                                       Duration::from_hours(6));
+    let token = common::make_token("client".to_string(), name.to_string(), "".to_string(), ns.clone(),
+                                   false, Duration::from_hours(6));
     if token.is_some() {
         let token2 = token.clone();
         let mut p = protodef.unwrap().lock().unwrap();
-        p.cltk = token;
+        p.cltk = token.as_deref().map(common::secret_digest);
+        audit::record(audit::AuditEvent {
+            event: "token_issued",
+            namespace: ns,
+            protobuf_name: name,
+            user: "client".to_string(),
+            token_id: audit::token_id(token2.as_deref().unwrap()),
+            ..Default::default()
+        });
         let response = registry::AuthorizeResponse {
             token: token2.unwrap(),
             status: None,
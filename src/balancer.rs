@@ -0,0 +1,225 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+use rand::Rng;
+
+// Service selection used by FIND. The routines here work on plain slices so
+// the caller can hold the protobuf lock while choosing, without the balancer
+// having to know about the Mutex wrapped service list.
+
+// Number of points each service instance occupies on the hash ring. More
+// points spread the keys more evenly between instances.
+const RING_POINTS: u32 = 160;
+
+// FNV-1a 64 bit hash. A fixed hash is used instead of the std hasher so
+// key placement is the same across restarts and registry builds.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in data {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Build the consistent hash ring for the service urls. Each entry is the
+// point on the ring and the index of the owning url. Points are derived
+// from the url only, so adding or removing an instance only moves the keys
+// that land next to its own points.
+pub fn build_ring(urls: &[String]) -> Vec<(u64, usize)> {
+    let mut ring = Vec::with_capacity(urls.len() * RING_POINTS as usize);
+    for (idx, url) in urls.iter().enumerate() {
+        for point in 0..RING_POINTS {
+            let vnode = format!("{}#{}", url, point);
+            ring.push((fnv1a(vnode.as_bytes()), idx));
+        }
+    }
+    ring.sort();
+    ring
+}
+
+// Hash ring kept on the protobuf with the urls it was built for, so FIND only
+// rebuilds it when the instances it chooses from change.
+#[derive(Debug, Default)]
+pub struct Ring {
+    urls: Vec<String>,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    pub fn new(urls: &[String]) -> Ring {
+        Ring { urls: urls.to_vec(), points: build_ring(urls) }
+    }
+
+    // Rebuild the ring when the urls differ from the ones it was built for.
+    pub fn update(&mut self, urls: &[String]) {
+        if self.urls != urls {
+            *self = Ring::new(urls);
+        }
+    }
+
+    // Pick the service owning the hash key. The key is placed on the ring and
+    // the first instance point at or after it (wrapping around) is chosen.
+    pub fn pick(&self, key: &str) -> Option<usize> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let hash = fnv1a(key.as_bytes());
+        let pos = self.points.partition_point(|(point, _)| *point < hash);
+        let (_, idx) = self.points[pos % self.points.len()];
+        Some(idx)
+    }
+
+    // Order every service by walking the ring from the hash key. The first
+    // entry is the sticky owner, the rest is the failover order for the key.
+    pub fn rank(&self, key: &str) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.urls.len());
+        if self.points.is_empty() {
+            return order;
+        }
+        let hash = fnv1a(key.as_bytes());
+        let start = self.points.partition_point(|(point, _)| *point < hash);
+        for i in 0..self.points.len() {
+            let (_, idx) = self.points[(start + i) % self.points.len()];
+            if !order.contains(&idx) {
+                order.push(idx);
+                if order.len() == self.urls.len() {
+                    break;
+                }
+            }
+        }
+        order
+    }
+}
+
+// Live load of an instance. The request count reported by the last keepalive
+// goes stale between keepalives, so the hand-outs made since then are added
+// to keep clients from piling onto the same instance.
+pub fn live_load(requests: i32, handed_out: i32) -> i64 {
+    requests as i64 + handed_out as i64
+}
+
+// Power of two choices. Two distinct instances are sampled at random and the
+// lighter one is picked. This avoids the herding that comes from every client
+// choosing the single least loaded instance.
+pub fn pick_two_choices(loads: &[i64]) -> Option<usize> {
+    let ct = loads.len();
+    if ct < 2 {
+        return if ct == 1 { Some(0) } else { None };
+    }
+    let mut rng = rand::thread_rng();
+    let first = rng.gen_range(0..ct);
+    // draw the second from the remaining instances so the two always differ
+    let mut second = rng.gen_range(0..ct - 1);
+    if second >= first {
+        second += 1;
+    }
+    if loads[second] < loads[first] {
+        return Some(second);
+    }
+    Some(first)
+}
+
+// Order the instances by ascending live load. Equal loads keep list order.
+pub fn rank_by_load(loads: &[i64]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..loads.len()).collect();
+    order.sort_by_key(|idx| loads[*idx]);
+    order
+}
+
+// The power of two choices winner first, then the remaining instances by
+// ascending live load as failover candidates.
+pub fn rank_two_choices(loads: &[i64]) -> Vec<usize> {
+    let winner = match pick_two_choices(loads) {
+        Some(w) => w,
+        None => return Vec::new(),
+    };
+    let mut order = vec![winner];
+    for idx in rank_by_load(loads) {
+        if idx != winner {
+            order.push(idx);
+        }
+    }
+    order
+}
+
+#[test]
+fn test_rank_orders() {
+    assert_eq!(rank_by_load(&[5, 1, 99, 1]), vec![1, 3, 0, 2]);
+    let order = rank_two_choices(&[10, 3]);
+    assert_eq!(order, vec![1, 0]);
+    let urls: Vec<String> = (0..4).map(|i| format!("host-{}:9000", i)).collect();
+    let order = Ring::new(&urls).rank("tenant-7");
+    assert_eq!(order.len(), 4);
+    assert_eq!(order[0], Ring::new(&urls).pick("tenant-7").unwrap());
+}
+
+#[test]
+fn test_ring_rebuilt_on_change() {
+    let urls: Vec<String> = (0..4).map(|i| format!("host-{}:9000", i)).collect();
+    let mut ring = Ring::default();
+    assert_eq!(ring.pick("tenant"), None);
+    ring.update(&urls);
+    assert_eq!(ring.rank("tenant-7"), Ring::new(&urls).rank("tenant-7"));
+    let fewer = urls[1..].to_vec();
+    ring.update(&fewer);
+    assert_eq!(ring.rank("tenant-7"), Ring::new(&fewer).rank("tenant-7"));
+    assert_eq!(ring.rank("tenant-7").len(), 3);
+}
+
+#[test]
+fn test_pick_two_choices() {
+    assert_eq!(pick_two_choices(&[]), None);
+    assert_eq!(pick_two_choices(&[7]), Some(0));
+    // with two instances both are always sampled so the lighter one wins
+    for _ in 0..50 {
+        assert_eq!(pick_two_choices(&[10, 3]), Some(1));
+    }
+    // the heaviest instance can never win a comparison
+    for _ in 0..200 {
+        let idx = pick_two_choices(&[5, 1, 99, 4]).unwrap();
+        assert_ne!(idx, 2, "heaviest instance picked");
+    }
+}
+
+#[test]
+fn test_pick_by_hash_is_sticky() {
+    let urls: Vec<String> = (0..5).map(|i| format!("host-{}:9000", i)).collect();
+    for k in 0..100 {
+        let key = format!("tenant-{}", k);
+        let a = Ring::new(&urls).pick(&key);
+        let b = Ring::new(&urls).pick(&key);
+        assert_eq!(a, b, "same key must pick the same instance");
+    }
+    assert_eq!(Ring::new(&Vec::new()).pick("tenant"), None);
+}
+
+#[test]
+fn test_pick_by_hash_minimal_movement() {
+    let urls: Vec<String> = (0..5).map(|i| format!("host-{}:9000", i)).collect();
+    let mut fewer = urls.clone();
+    let removed = fewer.remove(2);
+    for k in 0..1000 {
+        let key = format!("tenant-{}", k);
+        let before = &urls[Ring::new(&urls).pick(&key).unwrap()];
+        let after = &fewer[Ring::new(&fewer).pick(&key).unwrap()];
+        // only keys owned by the removed instance may move
+        if *before != removed {
+            assert_eq!(before, after, "key {} moved", key);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Optional active health checker. Instead of trusting keepalives alone the
+// registry calls the standard grpc.health.v1.Health/Check on every registered
+// service url. Failing instances are marked not serving and are evicted once
+// they fail the configured number of checks in a row.
+
+use std::time::Duration;
+use tonic::transport::Endpoint;
+use tonic_health::pb::health_client::HealthClient;
+use tonic_health::pb::health_check_response::ServingStatus;
+use prost::Message;
+use tonic_health::pb::HealthCheckRequest;
+use crate::common::HealthEnum;
+use crate::registry::{HealthCheckBatch, HealthCheckResult};
+use crate::{audit, cluster, common, metrics, Protobufs, GDATA};
+
+// Check a single instance. Returns true only when the instance answers
+// within the timeout and reports SERVING for the overall server health.
+pub async fn check_instance(url: &str, timeout: Duration) -> bool {
+    let target = if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("http://{}", url)
+    };
+    let endpoint = match Endpoint::from_shared(target) {
+        Ok(e) => e.connect_timeout(timeout).timeout(timeout),
+        Err(_) => return false,
+    };
+    let channel = match endpoint.connect().await {
+        Ok(c) => c,
+        Err(_) => return false,
+    };
+    let mut client = HealthClient::new(channel);
+    let request = HealthCheckRequest {
+        service: "".to_string(),
+    };
+    match client.check(request).await {
+        Ok(rsp) => rsp.into_inner().status == ServingStatus::Serving as i32,
+        Err(_) => false,
+    }
+}
+
+// Record the outcome of a check against the registry state. The protobufs
+// structure must already be locked. Returns true when the instance was
+// evicted. A protobuf left without services is released the same way
+// deregistration does it.
+pub fn apply_check(protobufs: &mut Protobufs, namespace: &str, protobuf_name: &str, id: &str,
+                   healthy: bool, max_failures: i32) -> bool {
+    let mut evicted = false;
+    let mut empty = false;
+    if let Some(m) = common::find_protobuf(protobufs, namespace, protobuf_name.to_string()) {
+        let mut protobuf = m.lock().unwrap();
+        let pos = protobuf.services.iter().position(|s| s.lock().unwrap().id == id);
+        if let Some(idx) = pos {
+            let mut svc = protobuf.services[idx].lock().unwrap();
+            if healthy {
+                // only lift a not serving state the checker set itself
+                if svc.hcf > 0 && svc.hst == HealthEnum::NOTSERVING {
+                    svc.hst = HealthEnum::SERVING;
+                }
+                svc.hcf = 0;
+            } else {
+                svc.hcf += 1;
+                svc.hst = HealthEnum::NOTSERVING;
+                evicted = svc.hcf >= max_failures;
+            }
+            drop(svc);
+            if evicted {
+                protobuf.services.remove(idx);
+                empty = protobuf.services.is_empty();
+            }
+        }
+    }
+    if empty {
+        common::release_protobuf_group(protobufs, namespace, protobuf_name);
+    }
+    evicted
+}
+
+// Run one pass over every registered instance. The targets are copied out
+// first so the registry lock is not held while the checks are in flight.
+// In a cluster only the leader checks and the results of a pass go through
+// the cluster log as one entry.
+pub async fn check_all(timeout: Duration, max_failures: i32) {
+    if !cluster::is_leader() {
+        return;
+    }
+    let mut targets = Vec::new();
+    {
+        let protobufs = GDATA.get().unwrap().lock().unwrap();
+        for (ns, protomap) in protobufs.namespaces.iter() {
+            for (name, m) in protomap.iter() {
+                let protobuf = m.lock().unwrap();
+                for s in protobuf.services.iter() {
+                    let svc = s.lock().unwrap();
+                    targets.push((ns.clone(), name.clone(), svc.id.clone(), svc.url.clone()));
+                }
+            }
+        }
+    }
+
+    let mut checks = Vec::new();
+    for (ns, name, id, url) in targets {
+        checks.push(tokio::spawn(async move {
+            let healthy = check_instance(&url, timeout).await;
+            HealthCheckResult { namespace: ns, protobuf_name: name, instance_id: id, url, healthy }
+        }));
+    }
+    let mut batch = HealthCheckBatch { results: Vec::new(), max_failures };
+    for check in checks {
+        if let Ok(result) = check.await {
+            batch.results.push(result);
+        }
+    }
+    if batch.results.is_empty() {
+        return;
+    }
+    if !cluster::enabled() {
+        apply_checks(&batch);
+    } else if let Err(e) = cluster::write("checks", batch.encode_to_vec(), None).await {
+        tracing::warn!(error = %e.message(), "health check results not replicated");
+    }
+}
+
+// Record the results of a pass, evicting the instances that failed too many
+// checks in a row.
+pub fn apply_checks(batch: &HealthCheckBatch) {
+    for result in batch.results.iter() {
+        let (ns, name, id, url) = (&result.namespace, &result.protobuf_name, &result.instance_id, &result.url);
+        let mut protobufs = GDATA.get().unwrap().lock().unwrap();
+        if apply_check(&mut protobufs, ns, name, id, result.healthy, batch.max_failures) {
+            metrics::eviction("health_check");
+            tracing::info!(namespace = %ns, protobuf_name = %name, url = %url, instance_id = %id,
+                           failures = batch.max_failures, "evicted instance after failed health checks");
+            audit::record(audit::AuditEvent {
+                event: "evict",
+                namespace: ns.clone(),
+                protobuf_name: name.clone(),
+                url: url.clone(),
+                instance_id: id.clone(),
+                reason: format!("{} failed health checks", batch.max_failures),
+                ..Default::default()
+            });
+        }
+    }
+}
+
+// Checker loop started from main when health_check_interval is configured.
+pub async fn run_checker(interval: Duration, timeout: Duration, max_failures: i32) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        check_all(timeout, max_failures).await;
+    }
+}
+
+#[test]
+fn test_apply_check_evicts_after_failures() {
+    let mut protobufs = Protobufs {
+        namespaces: std::collections::HashMap::new(),
+    };
+    crate::common::add_protobuf(&mut protobufs, "", "proto1".to_string()).unwrap();
+    let (id1, id2) = {
+        let mut p = crate::common::find_protobuf(&protobufs, "", "proto1".to_string()).unwrap().lock().unwrap();
+        crate::common::add_service(&mut p, "url1".to_string(), None).unwrap();
+        crate::common::add_service(&mut p, "url2".to_string(), None).unwrap();
+        let id = |idx: usize| p.services[idx].lock().unwrap().id.clone();
+        (id(0), id(1))
+    };
+    assert!(!apply_check(&mut protobufs, "", "proto1", &id1, false, 2));
+    {
+        let p = crate::common::find_protobuf(&protobufs, "", "proto1".to_string()).unwrap().lock().unwrap();
+        let svc = p.services[0].lock().unwrap();
+        assert_eq!(svc.hst, HealthEnum::NOTSERVING);
+    }
+    // a good check brings it back
+    assert!(!apply_check(&mut protobufs, "", "proto1", &id1, true, 2));
+    {
+        let p = crate::common::find_protobuf(&protobufs, "", "proto1".to_string()).unwrap().lock().unwrap();
+        let svc = p.services[0].lock().unwrap();
+        assert_eq!(svc.hst, HealthEnum::SERVING);
+    }
+    assert!(!apply_check(&mut protobufs, "", "proto1", &id1, false, 2));
+    assert!(apply_check(&mut protobufs, "", "proto1", &id1, false, 2));
+    assert!(apply_check(&mut protobufs, "", "proto1", &id2, false, 1));
+    assert!(protobufs.namespaces.is_empty(), "empty protobuf not removed");
+}
+
+#[tokio::test]
+async fn test_check_instance_against_health_server() {
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    // local stand-in for a registered provider
+    let (mut reporter, health_service) = tonic_health::server::health_reporter();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(tonic::transport::Server::builder()
+        .add_service(health_service)
+        .serve_with_incoming(TcpListenerStream::new(listener)));
+
+    let url = addr.to_string();
+    let timeout = Duration::from_secs(2);
+    assert!(check_instance(&url, timeout).await, "serving instance failed check");
+    reporter.set_service_status("", tonic_health::ServingStatus::NotServing).await;
+    assert!(!check_instance(&url, timeout).await, "not serving instance passed check");
+    assert!(!check_instance("127.0.0.1:1", timeout).await, "unreachable instance passed check");
+}
@@ -17,15 +17,715 @@
 
 // This client is a test-bed for the gRPC registry service. It exercises server
 // functions and provides a working example of invoking a service through gRPC
-// requests.
+// requests. It is also the operator command line tool for the registry.
 
 
 use std::collections::HashMap;
+use std::collections::BTreeMap;
+use clap::{Args, Parser, Subcommand};
+use prost::Message;
 use registry:: registry_client::RegistryClient;
 
 pub mod registry {
     tonic::include_proto!("registry");
 }
+pub mod propagation;
+pub mod export;
+
+// Registry client sending the caller's trace context with every request.
+type TraceInterceptor = fn(tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status>;
+pub type TracedClient = RegistryClient<InterceptedService<Channel, TraceInterceptor>>;
+
+#[derive(Parser)]
+#[command(name = "reg-client", about = "Command line client for the gRPC registry")]
+struct Cli {
+    /// Registry address, overrides server_address from the configuration
+    #[arg(long, global = true, env = "REGISTRY_SERVER")]
+    server: Option<String>,
+    /// Configuration file
+    #[arg(long, global = true, default_value = "setting.toml")]
+    config: String,
+    /// Output format: table, json or yaml, report also takes csv
+    #[arg(long, global = true, default_value = "table")]
+    format: String,
+    /// Namespace of the protobuf names, the default namespace when not given
+    #[arg(long, global = true, env = "REGISTRY_NAMESPACE", default_value = "")]
+    namespace: String,
+    #[command(subcommand)]
+    command: Command,
+}
+
+// Where a command gets its token. --token or REGISTRY_TOKEN is used first,
+// then --token-file or REGISTRY_TOKEN_FILE.
+#[derive(Args)]
+struct TokenArgs {
+    /// Registry token
+    #[arg(long, env = "REGISTRY_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// File holding the registry token
+    #[arg(long, env = "REGISTRY_TOKEN_FILE")]
+    token_file: Option<String>,
+}
+
+impl TokenArgs {
+    fn resolve(&self) -> Result<String, String> {
+        if let Some(t) = &self.token {
+            return Ok(t.clone());
+        }
+        match &self.token_file {
+            Some(file) => match std::fs::read_to_string(file) {
+                Ok(t) => Ok(t.trim().to_string()),
+                Err(e) => Err(format!("failed to read token file '{}': {}", file, e)),
+            },
+            None => Err("a token is required: --token, --token-file, REGISTRY_TOKEN or REGISTRY_TOKEN_FILE".to_string()),
+        }
+    }
+}
+
+// Instance filters shared by report and watch.
+#[derive(Args)]
+struct FilterArgs {
+    /// Only protobuf names starting with this
+    #[arg(long, default_value = "")]
+    name_prefix: String,
+    /// Only instances with this key=value label, repeatable
+    #[arg(long = "label")]
+    labels: Vec<String>,
+    /// Only instances in this health state, repeatable
+    #[arg(long)]
+    health: Vec<String>,
+}
+
+impl FilterArgs {
+    fn request(&self, token: String, namespace: &str) -> Result<registry::ProviderReportRequest, String> {
+        let mut request = registry::ProviderReportRequest {
+            token,
+            namespace: namespace.to_string(),
+            name_prefix: self.name_prefix.clone(),
+            ..Default::default()
+        };
+        for label in self.labels.iter() {
+            let (k, v) = export::parse_label(label)?;
+            request.labels.insert(k, v);
+        }
+        for h in self.health.iter() {
+            request.health.push(export::parse_health(h)?);
+        }
+        Ok(request)
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Get a client token for FIND on a protobuf name
+    Auth {
+        /// Protobuf name
+        name: String,
+        /// Write the token to this file
+        #[arg(long)]
+        save_token: Option<String>,
+    },
+    /// Register a service instance
+    Register {
+        /// Protobuf name
+        name: String,
+        /// Service url (host:port)
+        url: String,
+        /// key=value label returned with FIND results, repeatable
+        #[arg(long = "label")]
+        labels: Vec<String>,
+        /// Semver API version
+        #[arg(long, default_value = "")]
+        version: String,
+        /// File holding the serialized FileDescriptorSet of the API
+        #[arg(long)]
+        schema: Option<String>,
+        /// Key of this provider process, another key cannot take over the url
+        #[arg(long, default_value = "")]
+        instance_key: String,
+        /// Instance id of an earlier registration to replace, also at a new url
+        #[arg(long, default_value = "")]
+        instance_id: String,
+        /// Add an instance even when the url is registered, for processes behind a proxy
+        #[arg(long)]
+        shared_url: bool,
+        /// Write the registration token to this file
+        #[arg(long)]
+        save_token: Option<String>,
+    },
+    /// Remove the registration the token was issued for
+    Deregister {
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Find instances of a protobuf name
+    Find {
+        /// Protobuf name
+        name: String,
+        #[command(flatten)]
+        token: TokenArgs,
+        /// Sticky selection key
+        #[arg(long, default_value = "")]
+        hash_key: String,
+        /// Rotate through the instances
+        #[arg(long)]
+        round_robin: bool,
+        /// Prefer the instance with the lowest load
+        #[arg(long)]
+        lowest_use: bool,
+        /// Pick the lighter of two random instances
+        #[arg(long)]
+        two_choices: bool,
+        /// Number of candidates returned
+        #[arg(long, default_value_t = 1)]
+        max_results: i32,
+        /// Semver requirement, e.g. ^2.1
+        #[arg(long, default_value = "")]
+        version_req: String,
+    },
+    /// Send a keepalive for the registration the token was issued for
+    Alive {
+        #[command(flatten)]
+        token: TokenArgs,
+        /// Requests handled since the last keepalive
+        #[arg(long, default_value_t = 0)]
+        requests: i32,
+        /// Health state: serving, draining or not_serving
+        #[arg(long, default_value = "serving")]
+        health: String,
+    },
+    /// Print the provider report
+    Report {
+        #[command(flatten)]
+        token: TokenArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Protobuf names per page, 0 for all
+        #[arg(long, default_value_t = 0)]
+        page_size: i32,
+        /// Page token printed with the previous page
+        #[arg(long, default_value = "")]
+        page_token: String,
+    },
+    /// Poll the report and print instances as they come, go and change health
+    Watch {
+        #[command(flatten)]
+        token: TokenArgs,
+        #[command(flatten)]
+        filter: FilterArgs,
+        /// Seconds between polls
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+    },
+    /// Fetch the stored schema of a protobuf name
+    Schema {
+        /// Protobuf name
+        name: String,
+        #[command(flatten)]
+        token: TokenArgs,
+        /// Schema version, 0 for the latest
+        #[arg(long, default_value_t = 0)]
+        version: i32,
+        /// Write the FileDescriptorSet to this file
+        #[arg(long)]
+        output: Option<String>,
+    },
+    /// Set the schema compatibility mode of a protobuf name
+    Compat {
+        /// Protobuf name
+        name: String,
+        /// default, backward, forward, full or none
+        mode: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Admin functions, using a token from admin auth
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// Get an admin token for the admin_secret of the registry
+    Auth {
+        /// admin_secret from the registry configuration
+        #[arg(long, env = "REGISTRY_ADMIN_SECRET", hide_env_values = true)]
+        secret: String,
+        /// Write the token to this file
+        #[arg(long)]
+        save_token: Option<String>,
+    },
+    /// Remove an instance whatever token it registered with
+    Evict {
+        /// Protobuf name
+        name: String,
+        /// Service url, when no instance id is given
+        url: Option<String>,
+        /// Instance id
+        #[arg(long, default_value = "")]
+        id: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Remove a protobuf name with all its instances and schemas
+    Purge {
+        /// Protobuf name
+        name: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Keep an instance, or every instance of the name without a url or id, out of FIND
+    Cordon {
+        /// Protobuf name
+        name: String,
+        /// Service url, all instances when neither url nor id is given
+        url: Option<String>,
+        /// Instance id
+        #[arg(long, default_value = "")]
+        id: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Return a cordoned instance or protobuf name to FIND
+    Uncordon {
+        /// Protobuf name
+        name: String,
+        /// Service url, the protobuf name cordon when neither url nor id is given
+        url: Option<String>,
+        /// Instance id
+        #[arg(long, default_value = "")]
+        id: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+    /// Clear the client token kept for a protobuf name
+    Revoke {
+        /// Protobuf name
+        name: String,
+        #[command(flatten)]
+        token: TokenArgs,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(e) = run(cli).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn run(cli: Cli) -> Result<(), String> {
+    let format = export::ReportFormat::from_name(&cli.format)
+        .ok_or(format!("unknown format '{}'", cli.format))?;
+    let server = match cli.server {
+        Some(s) => s,
+        None => load_config(&cli.config).get("server_address").cloned()
+            .ok_or(format!("server_address is not set in {}, use --server", cli.config))?,
+    };
+    let mut client = connect(&server).await?;
+    let namespace = cli.namespace;
+
+    let output = match cli.command {
+        Command::Auth { name, save_token } => {
+            let request = registry::AuthorizeRequest {
+                protobuf_name: name,
+                namespace,
+            };
+            let rsp = call(client.auth(request).await)?;
+            check_status(rsp.status)?;
+            save(&save_token, &rsp.token)?;
+            serde_json::json!({ "token": rsp.token })
+        },
+        Command::Register { name, url, labels, version, schema, instance_key, instance_id, shared_url,
+                            save_token } => {
+            let mut request = registry::RegisterRequest {
+                protobuf_name: name,
+                protobuf_url: url,
+                metadata: HashMap::new(),
+                file_descriptor_set: Vec::new(),
+                version,
+                instance_key,
+                instance_id,
+                shared_url,
+                namespace,
+            };
+            for label in labels {
+                let (k, v) = export::parse_label(&label)?;
+                request.metadata.insert(k, v);
+            }
+            if let Some(file) = schema {
+                request.file_descriptor_set = std::fs::read(&file)
+                    .map_err(|e| format!("failed to read schema file '{}': {}", file, e))?;
+            }
+            let rsp = call(client.regs(request).await)?;
+            check_status(rsp.status)?;
+            save(&save_token, &rsp.token)?;
+            serde_json::json!({
+                "token": rsp.token,
+                "schema_version": rsp.schema_version,
+                "instance_id": rsp.instance_id,
+            })
+        },
+        Command::Deregister { token } => {
+            let request = registry::DeRegisterRequest {
+                token: token.resolve()?,
+                namespace,
+            };
+            let rsp = call(client.unreg(request).await)?;
+            check_status(rsp.status)?;
+            serde_json::json!({ "status": "SUCCESS" })
+        },
+        Command::Find { name, token, hash_key, round_robin, lowest_use, two_choices, max_results, version_req } => {
+            let request = registry::FindProviderRequest {
+                registry_token: token.resolve()?,
+                protobuf_name: name,
+                by_round_robin: round_robin,
+                by_lowest_use: lowest_use,
+                hash_key,
+                by_two_choices: two_choices,
+                max_results,
+                version_req,
+                namespace,
+            };
+            let rsp = call(client.find(request).await)?;
+            check_status(rsp.status)?;
+            let instances: Vec<serde_json::Value> = rsp.instances.iter().map(|i| serde_json::json!({
+                "instance_id": i.instance_id,
+                "service_url": i.service_url,
+                "version": i.version,
+                "requests": i.requests,
+                "handed_out": i.handed_out,
+                "metadata": labels_text(&i.metadata),
+            })).collect();
+            serde_json::json!({ "instances": instances })
+        },
+        Command::Alive { token, requests, health } => {
+            let request = registry::KeepaliveReport {
+                token: token.resolve()?,
+                number_requests: requests,
+                health: export::parse_health(&health)?,
+                namespace,
+            };
+            let rsp = call(client.alive(request).await)?;
+            check_status(rsp.status)?;
+            serde_json::json!({ "status": "SUCCESS" })
+        },
+        Command::Report { token, filter, page_size, page_token } => {
+            let mut request = filter.request(token.resolve()?, &namespace)?;
+            request.page_size = page_size;
+            request.page_token = page_token;
+            let rsp = call(client.report(request).await)?;
+            check_status(rsp.status.clone())?;
+            print!("{}", export::render_report(&rsp, format)?);
+            return Ok(());
+        },
+        Command::Watch { token, filter, interval } => {
+            let request = filter.request(token.resolve()?, &namespace)?;
+            return watch(&mut client, request, interval, format).await;
+        },
+        Command::Schema { name, token, version, output } => {
+            let request = registry::SchemaRequest {
+                token: token.resolve()?,
+                protobuf_name: name,
+                version,
+                namespace,
+            };
+            let rsp = call(client.schema(request).await)?;
+            check_status(rsp.status)?;
+            if let Some(file) = &output {
+                std::fs::write(file, &rsp.file_descriptor_set)
+                    .map_err(|e| format!("failed to write to file '{}': {}", file, e))?;
+            }
+            let files: Vec<String> = prost_types::FileDescriptorSet::decode(rsp.file_descriptor_set.as_slice())
+                .map(|fds| fds.file.iter().map(|f| f.name().to_string()).collect())
+                .unwrap_or_default();
+            serde_json::json!({
+                "protobuf_name": rsp.protobuf_name,
+                "version": rsp.version,
+                "versions": rsp.versions,
+                "files": files,
+            })
+        },
+        Command::Compat { name, mode, token } => {
+            let mode_name = match mode.to_uppercase().as_str() {
+                "DEFAULT" => "COMPAT_DEFAULT".to_string(),
+                other => other.to_string(),
+            };
+            let mode = registry::CompatibilityMode::from_str_name(&mode_name)
+                .ok_or(format!("unknown compatibility mode '{}'", mode))?;
+            let request = registry::CompatRequest {
+                token: token.resolve()?,
+                protobuf_name: name,
+                mode: mode as i32,
+                namespace,
+            };
+            let rsp = call(client.compat(request).await)?;
+            check_status(rsp.status)?;
+            let mode = registry::CompatibilityMode::try_from(rsp.mode)
+                .map(|m| m.as_str_name().to_string())
+                .unwrap_or(rsp.mode.to_string());
+            serde_json::json!({ "mode": mode })
+        },
+        Command::Admin { command } => admin(&mut client, command, namespace).await?,
+    };
+    print!("{}", render_output(&output, format)?);
+    Ok(())
+}
+
+async fn admin(client: &mut TracedClient, command: AdminCommand, namespace: String)
+    -> Result<serde_json::Value, String> {
+    let rsp = match command {
+        AdminCommand::Auth { secret, save_token } => {
+            let rsp = call(client.adminauth(registry::AdminAuthRequest { secret, namespace }).await)?;
+            check_status(rsp.status)?;
+            save(&save_token, &rsp.token)?;
+            return Ok(serde_json::json!({ "token": rsp.token }));
+        },
+        AdminCommand::Evict { name, url, id, token } => {
+            let request = registry::RemoveInstanceRequest {
+                token: token.resolve()?,
+                protobuf_name: name,
+                service_url: url.unwrap_or_default(),
+                instance_id: id,
+                namespace,
+            };
+            call(client.evict(request).await)?
+        },
+        AdminCommand::Purge { name, token } => {
+            let request = registry::RemoveProtobufRequest {
+                token: token.resolve()?,
+                protobuf_name: name,
+                namespace,
+            };
+            call(client.purge(request).await)?
+        },
+        AdminCommand::Cordon { name, url, id, token } => {
+            call(client.cordon(cordon_request(name, url, id, token, namespace, false)?).await)?
+        },
+        AdminCommand::Uncordon { name, url, id, token } => {
+            call(client.cordon(cordon_request(name, url, id, token, namespace, true)?).await)?
+        },
+        AdminCommand::Revoke { name, token } => {
+            let request = registry::ClearClientTokenRequest {
+                token: token.resolve()?,
+                protobuf_name: name,
+                namespace,
+            };
+            call(client.revoke(request).await)?
+        },
+    };
+    check_status(rsp.status)?;
+    Ok(serde_json::json!({ "status": "SUCCESS", "removed": rsp.removed }))
+}
+
+fn cordon_request(name: String, url: Option<String>, id: String, token: TokenArgs, namespace: String,
+                  uncordon: bool) -> Result<registry::CordonRequest, String> {
+    Ok(registry::CordonRequest {
+        token: token.resolve()?,
+        protobuf_name: name,
+        service_url: url.unwrap_or_default(),
+        uncordon,
+        instance_id: id,
+        namespace,
+    })
+}
+
+// Unwrap the gRPC result of a call.
+fn call<T>(result: Result<tonic::Response<T>, tonic::Status>) -> Result<T, String> {
+    match result {
+        Ok(r) => Ok(r.into_inner()),
+        Err(e) => Err(format!("request failed: {:?}: {}", e.code(), e.message())),
+    }
+}
+
+// Turn an error status packet into an error naming the status code.
+fn check_status(status: Option<registry::StatusPacket>) -> Result<(), String> {
+    match status {
+        None => Ok(()),
+        Some(s) => {
+            let code = registry::StatusCodes::try_from(s.code)
+                .map(|c| c.as_str_name().to_string())
+                .unwrap_or(s.code.to_string());
+            Err(format!("{}: {}", code, s.error_message))
+        },
+    }
+}
+
+// Write a token to a file only the current user can read.
+fn save(file: &Option<String>, token: &str) -> Result<(), String> {
+    let file = match file {
+        Some(f) => f,
+        None => return Ok(()),
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let written = options.open(file).and_then(|mut f| std::io::Write::write_all(&mut f, token.as_bytes()));
+    written.map_err(|e| format!("failed to write to file '{}': {}", file, e))
+}
+
+fn labels_text(labels: &HashMap<String, String>) -> String {
+    let mut pairs: Vec<String> = labels.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+    pairs.sort();
+    pairs.join(";")
+}
+
+// Render a command result. Tables print scalars as "key: value" lines and
+// lists of objects as aligned columns.
+fn render_output(value: &serde_json::Value, format: export::ReportFormat) -> Result<String, String> {
+    match format {
+        export::ReportFormat::JSON => serde_json::to_string_pretty(value)
+            .map(|s| s + "\n")
+            .map_err(|e| format!("failed to write json: {}", e)),
+        export::ReportFormat::YAML => serde_yaml::to_string(value)
+            .map_err(|e| format!("failed to write yaml: {}", e)),
+        export::ReportFormat::CSV => Err("csv output is only available for report".to_string()),
+        export::ReportFormat::TABLE => Ok(text_output(value)),
+    }
+}
+
+fn text_output(value: &serde_json::Value) -> String {
+    let scalar = |v: &serde_json::Value| match v {
+        serde_json::Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    let mut out = String::new();
+    let obj = match value.as_object() {
+        Some(o) => o,
+        None => return scalar(value) + "\n",
+    };
+    for (key, v) in obj {
+        let rows = match v.as_array() {
+            Some(rows) if rows.iter().all(|r| r.is_object()) => rows,
+            _ => {
+                out.push_str(&format!("{}: {}\n", key, scalar(v)));
+                continue;
+            },
+        };
+        let columns: Vec<String> = match rows.first().and_then(|r| r.as_object()) {
+            Some(first) => first.keys().cloned().collect(),
+            None => continue,
+        };
+        let cells: Vec<Vec<String>> = rows.iter()
+            .map(|r| columns.iter().map(|c| scalar(&r[c])).collect())
+            .collect();
+        let mut widths: Vec<usize> = columns.iter().map(|c| c.len()).collect();
+        for row in cells.iter() {
+            for (n, cell) in row.iter().enumerate() {
+                widths[n] = widths[n].max(cell.chars().count());
+            }
+        }
+        let line = |cells: Vec<String>| {
+            let padded: Vec<String> = cells.iter().enumerate()
+                .map(|(n, c)| format!("{:width$}", c, width = widths[n]))
+                .collect();
+            format!("{}\n", padded.join("  ").trim_end())
+        };
+        out.push_str(&line(columns.iter().map(|c| c.to_uppercase()).collect()));
+        for row in cells {
+            out.push_str(&line(row));
+        }
+    }
+    out
+}
+
+// Instance state compared between polls of watch: (protobuf, instance id)
+// to (url, health, version).
+type WatchState = BTreeMap<(String, String), (String, String, String)>;
+
+fn snapshot(rsp: &registry::ProviderReportResponse) -> WatchState {
+    let mut state = BTreeMap::new();
+    for p in rsp.providers.iter() {
+        for i in p.instances.iter() {
+            let health = registry::HealthState::try_from(i.health)
+                .map(|h| h.as_str_name().to_string())
+                .unwrap_or(i.health.to_string());
+            state.insert((p.protobuf_name.clone(), i.instance_id.clone()),
+                         (i.service_url.clone(), health, i.version.clone()));
+        }
+    }
+    state
+}
+
+// Differences between two snapshots as (event, protobuf, url, health, instance id) rows.
+fn changes(old: &WatchState, new: &WatchState) -> Vec<[String; 5]> {
+    let row = |event: &str, key: &(String, String), value: &(String, String, String)| {
+        [event.to_string(), key.0.clone(), value.0.clone(), value.1.clone(), key.1.clone()]
+    };
+    let mut events = Vec::new();
+    for (key, value) in new.iter() {
+        match old.get(key) {
+            None => events.push(row("ADDED", key, value)),
+            Some(v) if v != value => events.push(row("CHANGED", key, value)),
+            Some(_) => {},
+        }
+    }
+    for (key, value) in old.iter() {
+        if !new.contains_key(key) {
+            events.push(row("REMOVED", key, value));
+        }
+    }
+    events
+}
+
+// Poll the whole report, following pages, and print what changed until interrupted.
+async fn watch(client: &mut TracedClient, request: registry::ProviderReportRequest,
+               interval: u64, format: export::ReportFormat) -> Result<(), String> {
+    let mut previous = BTreeMap::new();
+    let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval.max(1)));
+    loop {
+        ticker.tick().await;
+        let mut current = BTreeMap::new();
+        let mut page = request.clone();
+        loop {
+            let rsp = call(client.report(page.clone()).await)?;
+            check_status(rsp.status.clone())?;
+            current.extend(snapshot(&rsp));
+            if rsp.next_page_token.is_empty() {
+                break;
+            }
+            page.page_token = rsp.next_page_token;
+        }
+        for [event, name, url, health, id] in changes(&previous, &current) {
+            match format {
+                export::ReportFormat::TABLE => println!("{:8} {} {} {} {}", event, name, url, health, id),
+                _ => println!("{}", serde_json::json!({
+                    "event": event, "protobuf_name": name, "service_url": url, "health": health,
+                    "instance_id": id,
+                })),
+            }
+        }
+        previous = current;
+    }
+}
+
+#[test]
+fn test_watch_changes() {
+    let entry = |id: &str, url: &str, health: &str| {
+        (("p".to_string(), id.to_string()), (url.to_string(), health.to_string(), "".to_string()))
+    };
+    let old = WatchState::from([entry("1", "a", "SERVING"), entry("2", "b", "SERVING"), entry("4", "d", "SERVING")]);
+    let new = WatchState::from([entry("1", "a", "DRAINING"), entry("3", "c", "SERVING"), entry("4", "e", "SERVING")]);
+    let events: Vec<String> = changes(&old, &new).iter().map(|e| format!("{} {}", e[0], e[2])).collect();
+    assert_eq!(events, vec!["CHANGED a", "ADDED c", "CHANGED e", "REMOVED b"]);
+    assert!(changes(&new, &new).is_empty());
+}
+
+#[test]
+fn test_text_output() {
+    let value = serde_json::json!({
+        "token": "abc",
+        "instances": [{"service_url": "localhost:8089", "requests": 3}],
+    });
+    let text = text_output(&value);
+    assert!(text.contains("token: abc\n"), "{}", text);
+    assert!(text.contains("REQUESTS  SERVICE_URL\n3         localhost:8089\n"), "{}", text);
+}
 
 #[tokio::test]
 async fn test_authorize_for_unknown_protobuf() {
@@ -33,6 +733,7 @@ async fn test_authorize_for_unknown_protobuf() {
 
     let request = tonic::Request::new(registry::AuthorizeRequest {
         protobuf_name: "unknown-testproto".to_string(),
+        namespace: "".to_string(),
     });
     let response = client.auth(request).await;
     let a = response.unwrap();
@@ -56,6 +757,13 @@ async fn test_register_service_functionality() {
     let request = tonic::Request::new(registry::RegisterRequest {
         protobuf_name: "testproto".to_string(),
         protobuf_url: "localhost:8089".to_string(),
+        metadata: HashMap::new(),
+        file_descriptor_set: Vec::new(),
+        version: "".to_string(),
+        instance_key: "".to_string(),
+        instance_id: "".to_string(),
+        shared_url: false,
+        namespace: "".to_string(),
     });
     let response = client.regs(request).await;
     let a = response.unwrap();
@@ -79,6 +787,11 @@ async fn test_register_service_functionality() {
         protobuf_name: "testproto".to_string(),
         by_round_robin: false,
         by_lowest_use: false,
+        hash_key: "".to_string(),
+        by_two_choices: false,
+        max_results: 0,
+        version_req: "".to_string(),
+        namespace: "".to_string(),
     });
     let response = client.find(request).await;
     let a = response.unwrap();
@@ -91,6 +804,8 @@ async fn test_register_service_functionality() {
     let request = tonic::Request::new(registry::KeepaliveReport {
         token: tok2,
         number_requests: 0,
+        health: registry::HealthState::Serving as i32,
+        namespace: "".to_string(),
     });
     let response = client.alive(request).await;
     let a = response.unwrap();
@@ -102,6 +817,7 @@ async fn test_register_service_functionality() {
     let tok3 = token.clone();
     let request = tonic::Request::new(registry::DeRegisterRequest {
         token: tok3,
+        namespace: "".to_string(),
     });
     let response = client.unreg(request).await;
     let a = response.unwrap();
@@ -112,6 +828,7 @@ async fn test_register_service_functionality() {
     let tok4 = token.clone();
     let request = tonic::Request::new(registry::ProviderReportRequest {
         token: tok4,
+        ..Default::default()
     });
     let response = client.report(request).await;
     let a = response.unwrap();
@@ -119,15 +836,79 @@ async fn test_register_service_functionality() {
     println!("{:?}", a);
 }
 
+#[tokio::test]
+async fn test_namespaces_are_separate() {
+    let mut client = grpc_connect().await;
+
+    let register = |namespace: &str| registry::RegisterRequest {
+        protobuf_name: "ns-testproto".to_string(),
+        protobuf_url: "localhost:8090".to_string(),
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+    let dev = client.regs(register("dev-test")).await.unwrap().into_inner();
+    assert!(dev.status.is_none(), "{:?}", dev.status);
+    // the same name and url in another namespace is another instance
+    let prod = client.regs(register("prod-test")).await.unwrap().into_inner();
+    assert!(prod.status.is_none(), "{:?}", prod.status);
+    assert_ne!(dev.instance_id, prod.instance_id);
+
+    // a token is only good in its own namespace
+    let find = |namespace: &str| registry::FindProviderRequest {
+        registry_token: dev.token.clone(),
+        protobuf_name: "ns-testproto".to_string(),
+        namespace: namespace.to_string(),
+        ..Default::default()
+    };
+    let rsp = client.find(find("dev-test")).await.unwrap().into_inner();
+    assert_eq!(rsp.service_url, "localhost:8090");
+    let rsp = client.find(find("prod-test")).await.unwrap().into_inner();
+    assert_eq!(rsp.status.unwrap().code, registry::StatusCodes::Autherror as i32);
+    let rsp = client.find(find("")).await.unwrap().into_inner();
+    assert_eq!(rsp.status.unwrap().code, registry::StatusCodes::Autherror as i32);
+
+    // the report covers the namespace of the request
+    let report = registry::ProviderReportRequest {
+        token: dev.token.clone(),
+        namespace: "dev-test".to_string(),
+        ..Default::default()
+    };
+    let rsp = client.report(report).await.unwrap().into_inner();
+    assert_eq!(rsp.namespace, "dev-test");
+    assert_eq!(rsp.providers.len(), 1);
+    assert_eq!(rsp.instances, 1);
+
+    for (token, namespace) in [(dev.token, "dev-test"), (prod.token, "prod-test")] {
+        let request = registry::DeRegisterRequest { token, namespace: namespace.to_string() };
+        let rsp = client.unreg(request).await.unwrap().into_inner();
+        assert!(rsp.status.is_none(), "{:?}", rsp.status);
+    }
+}
+
 // Setup the gRPC connection for performing tests.
 // Returns the gRPC client instance.
-pub async fn grpc_connect() -> RegistryClient<Channel> {
+// Connect to the registry at the given address.
+pub async fn connect(server_addr: &str) -> Result<TracedClient, String> {
+    let server_http = format!("http://{}", server_addr);
+    let endpoint = match Channel::from_shared(server_http) {
+        Ok(e) => e,
+        Err(e) => return Err(format!("invalid server address '{}': {}", server_addr, e)),
+    };
+    match endpoint.connect().await {
+        Ok(c) => Ok(RegistryClient::with_interceptor(c, propagation::inject_trace_context as TraceInterceptor)),
+        Err(e) => Err(format!("connection error to {}: {}", server_addr, e)),
+    }
+}
+
+pub async fn grpc_connect() -> TracedClient {
     let params = getconfig();
     let server_addr = params.get("server_address").unwrap();
     let server_http = format!("http://{}", server_addr);
     //println!("Connecting to gRPC Server at {}", server_http);
-    match RegistryClient::connect(server_http).await {
-        Ok(c) => { return c; },
+    match Channel::from_shared(server_http).unwrap().connect().await {
+        Ok(c) => {
+            return RegistryClient::with_interceptor(c, propagation::inject_trace_context as TraceInterceptor);
+        },
         Err(e) => {
             println!("connection error, tests cancelled: {}", e.to_string());
             std::process::exit(999);
@@ -137,15 +918,22 @@ pub async fn grpc_connect() -> RegistryClient<Channel> {
 
 // Load configuration parameters
 use config::{Config};
+use tonic::service::interceptor::InterceptedService;
 use tonic::transport::Channel;
 
 // Returns a hashmap of configuration parameters. The configuration file is
 // shared between the client and server so the same network address is used
 // in both.
 pub fn getconfig() -> HashMap<String, String> {
+    load_config("setting.toml")
+}
+
+// Load configuration parameters from the given file, which may be missing
+// when the CLI is pointed at a server with --server.
+fn load_config(path: &str) -> HashMap<String, String> {
     let settings = Config::builder()
         // Add in `./Settings.toml`
-        .add_source(config::File::with_name("setting.toml"))
+        .add_source(config::File::with_name(path).required(false))
         // Add in settings from the environment (with a prefix of APP)
         // Eg.. `APP_DEBUG=1 ./target/app` would set the `debug` key
         .add_source(config::Environment::with_prefix("APP"))
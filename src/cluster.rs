@@ -0,0 +1,1176 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Registry clustering. Nodes listed in cluster_peers elect a leader with Raft.
+// The leader takes the registry writes and appends each to the replicated log
+// as a command, the request with the clock and random seed of the leader.
+// Every node applies the committed commands in log order by running the same
+// handler, so a write shows nowhere before a majority of the nodes holds it.
+// Keepalives and health check results are collected by the leader and go into
+// the log in batches. The term, the vote and the log are written to
+// cluster_dir before a node answers for them. The log is compacted into a
+// snapshot of the registry every SNAPSHOT_ENTRIES entries, a node missing
+// compacted entries is sent the snapshot.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use once_cell::sync::{Lazy, OnceCell};
+use prost::Message;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Notify};
+use tokio::task::{JoinHandle, JoinSet};
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use tracing::{error, info, warn};
+use crate::registry::cluster_client::ClusterClient;
+use crate::registry::cluster_server::{Cluster, ClusterServer};
+use crate::registry::{AppendRequest, AppendResponse, Command, HealthCheckBatch, KeepaliveBatch, KeepaliveUpdate,
+                      LogEntry, Snapshot, SnapshotRequest, SnapshotResponse, VoteRequest, VoteResponse};
+use crate::{admin, audit, authorize, checker, common, compat, config_number, metrics, registrations, registry, state, GDATA};
+
+static NODE: OnceCell<Arc<Node>> = OnceCell::new();
+static COMMIT_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+// Keepalives taken since the last batch, the latest one of each instance.
+type InstanceKey = (String, String, String);
+static KEEPALIVES: Lazy<Mutex<HashMap<InstanceKey, KeepaliveUpdate>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Entries kept in the log before it is compacted into a snapshot.
+const SNAPSHOT_ENTRIES: usize = 1000;
+// Most entries sent to a node in one append.
+const MAX_APPEND: usize = 100;
+
+// The replicated state. The registry in production, a plain value in tests.
+pub trait StateMachine: Send + Sync {
+    // Apply a committed command and return its result. local is true on the
+    // node that took the command.
+    fn apply(&self, command: &[u8], local: bool) -> Vec<u8>;
+    fn snapshot(&self) -> Vec<u8>;
+    fn restore(&self, state: &[u8]);
+}
+
+struct Registry;
+
+impl StateMachine for Registry {
+    fn apply(&self, data: &[u8], local: bool) -> Vec<u8> {
+        let command = match Command::decode(data) {
+            Ok(c) => c,
+            Err(e) => {
+                error!(error = %e, "invalid command in the cluster log");
+                return Vec::new();
+            },
+        };
+        let peer = command.peer.parse().ok();
+        let result = common::replay(command.time, command.seed, !local,
+                                    || audit::with_peer(peer, || run_command(&command.method, &command.request)));
+        result.unwrap_or_else(|e| {
+            error!(method = %command.method, error = %e, "invalid request in the cluster log");
+            Vec::new()
+        })
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        let protobufs = GDATA.get().unwrap().lock().unwrap();
+        serde_json::to_vec(&state::snapshot(&protobufs)).unwrap_or_default()
+    }
+
+    fn restore(&self, data: &[u8]) {
+        match serde_json::from_slice(data) {
+            Ok(s) => *GDATA.get().unwrap().lock().unwrap() = state::restore(s),
+            Err(e) => error!(error = %e, "failed to apply replicated registry state"),
+        }
+    }
+}
+
+// Run the handler of a write from the cluster log. Returns the encoded response.
+fn run_command(method: &str, request: &[u8]) -> Result<Vec<u8>, prost::DecodeError> {
+    let response = match method {
+        "auth" => {
+            let req = registry::AuthorizeRequest::decode(request)?;
+            authorize::handle_authorize(&req.namespace, req.protobuf_name).encode_to_vec()
+        },
+        "regs" => registrations::handle_register(&registry::RegisterRequest::decode(request)?).encode_to_vec(),
+        "unreg" => registrations::handle_deregister(registry::DeRegisterRequest::decode(request)?).encode_to_vec(),
+        "compat" => compat::handle_compat(registry::CompatRequest::decode(request)?).encode_to_vec(),
+        "evict" => admin::handle_remove_instance(registry::RemoveInstanceRequest::decode(request)?).encode_to_vec(),
+        "purge" => admin::handle_remove_protobuf(registry::RemoveProtobufRequest::decode(request)?).encode_to_vec(),
+        "revoke" => admin::handle_clear_client_token(registry::ClearClientTokenRequest::decode(request)?)
+            .encode_to_vec(),
+        "cordon" => admin::handle_cordon(registry::CordonRequest::decode(request)?).encode_to_vec(),
+        "keepalives" => {
+            for update in KeepaliveBatch::decode(request)?.updates.iter() {
+                registrations::apply_keep_alive(update);
+            }
+            Vec::new()
+        },
+        "checks" => {
+            checker::apply_checks(&HealthCheckBatch::decode(request)?);
+            Vec::new()
+        },
+        _ => {
+            warn!(method, "unknown command in the cluster log");
+            Vec::new()
+        },
+    };
+    Ok(response)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Vote {
+    term: u64,
+    voted_for: Option<String>,
+}
+
+// The raft state on disk: the term and vote, the snapshot and the log
+// entries that follow it. Every write is synced before it returns.
+struct Store {
+    dir: PathBuf,
+    log: File,
+}
+
+impl Store {
+    fn open(dir: &Path) -> Result<(Store, Vote, Option<Snapshot>, Vec<LogEntry>), String> {
+        fs::create_dir_all(dir).map_err(|e| format!("failed to create '{}': {}", dir.display(), e))?;
+        let vote = match fs::read(dir.join("vote.json")) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| format!("invalid vote in '{}': {}", dir.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vote::default(),
+            Err(e) => return Err(format!("failed to read the vote in '{}': {}", dir.display(), e)),
+        };
+        let snapshot = match fs::read(dir.join("snapshot")) {
+            Ok(data) => Some(Snapshot::decode(data.as_slice())
+                .map_err(|e| format!("invalid snapshot in '{}': {}", dir.display(), e))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(format!("failed to read the snapshot in '{}': {}", dir.display(), e)),
+        };
+        let data = match fs::read(dir.join("log")) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("failed to read the log in '{}': {}", dir.display(), e)),
+        };
+        let mut entries = Vec::new();
+        let mut rest = data.as_slice();
+        while !rest.is_empty() {
+            match LogEntry::decode_length_delimited(&mut rest) {
+                Ok(entry) => entries.push(entry),
+                Err(_) => break,
+            }
+        }
+        let base = snapshot.as_ref().map(|s| s.last_index).unwrap_or(0);
+        entries.retain(|e| e.index > base);
+        let log = Store::open_log(dir)?;
+        let mut store = Store { dir: dir.to_path_buf(), log };
+        if !rest.is_empty() {
+            // a crash in the middle of an append, drop the partial entry
+            warn!(dir = %dir.display(), "dropping a partial entry at the end of the cluster log");
+            store.rewrite(&entries)?;
+        }
+        Ok((store, vote, snapshot, entries))
+    }
+
+    fn open_log(dir: &Path) -> Result<File, String> {
+        OpenOptions::new().create(true).append(true).open(dir.join("log"))
+            .map_err(|e| format!("failed to open the log in '{}': {}", dir.display(), e))
+    }
+
+    // Write a file in full, through a temporary file renamed over the old one.
+    fn replace(&self, name: &str, data: &[u8]) -> Result<(), String> {
+        let file = self.dir.join(name);
+        let tmpfile = self.dir.join(format!("{}.tmp", name));
+        let write = || -> std::io::Result<()> {
+            let mut f = File::create(&tmpfile)?;
+            f.write_all(data)?;
+            f.sync_all()?;
+            fs::rename(&tmpfile, &file)?;
+            File::open(&self.dir)?.sync_all()
+        };
+        write().map_err(|e| format!("failed to write '{}': {}", file.display(), e))
+    }
+
+    fn save_vote(&self, term: u64, voted_for: &Option<String>) -> Result<(), String> {
+        let vote = Vote { term, voted_for: voted_for.clone() };
+        self.replace("vote.json", &serde_json::to_vec(&vote).unwrap_or_default())
+    }
+
+    fn save_snapshot(&self, snapshot: &Snapshot) -> Result<(), String> {
+        self.replace("snapshot", &snapshot.encode_to_vec())
+    }
+
+    fn load_snapshot(&self) -> Option<Snapshot> {
+        fs::read(self.dir.join("snapshot")).ok().and_then(|data| Snapshot::decode(data.as_slice()).ok())
+    }
+
+    fn append(&mut self, entries: &[LogEntry]) -> Result<(), String> {
+        let mut data = Vec::new();
+        for entry in entries {
+            entry.encode_length_delimited(&mut data).map_err(|e| e.to_string())?;
+        }
+        self.log.write_all(&data).and_then(|_| self.log.sync_data())
+            .map_err(|e| format!("failed to append to the log in '{}': {}", self.dir.display(), e))
+    }
+
+    // Replace the log, after dropping entries that conflict with the leader
+    // or that a snapshot covers.
+    fn rewrite(&mut self, entries: &[LogEntry]) -> Result<(), String> {
+        let mut data = Vec::new();
+        for entry in entries {
+            entry.encode_length_delimited(&mut data).map_err(|e| e.to_string())?;
+        }
+        self.replace("log", &data)?;
+        self.log = Store::open_log(&self.dir)?;
+        Ok(())
+    }
+}
+
+struct Raft {
+    role: Role,
+    term: u64,
+    voted_for: Option<String>,
+    leader: String,     // server address of the leader, empty when unknown
+    base: (u64, u64),   // index and term of the last entry in the snapshot
+    entries: Vec<LogEntry>,     // entries after the snapshot
+    commit: u64,        // index of the last entry known committed
+    applied: u64,       // index of the last entry applied to the state machine
+    next: HashMap<String, u64>,     // next entry to send to each peer
+    matched: HashMap<String, u64>,  // last entry each peer is known to hold
+    waiting: HashMap<u64, (u64, oneshot::Sender<Vec<u8>>)>,    // writes taken here by index, with their term
+    heard: Instant,     // last append from a leader or vote granted
+    timeout: Duration,  // election timeout
+    store: Store,
+}
+
+impl Raft {
+    // Index and term of the last entry.
+    fn last(&self) -> (u64, u64) {
+        self.entries.last().map(|e| (e.index, e.term)).unwrap_or(self.base)
+    }
+
+    // Term of the entry at index, None when the node does not hold it.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.base.0) {
+            Some(0) => Some(self.base.1),
+            Some(n) => self.entries.get(n as usize - 1).map(|e| e.term),
+            None => None,
+        }
+    }
+
+    fn entry(&self, index: u64) -> Option<&LogEntry> {
+        match index.checked_sub(self.base.0) {
+            Some(0) | None => None,
+            Some(n) => self.entries.get(n as usize - 1),
+        }
+    }
+
+    fn save_vote(&self) -> Result<(), String> {
+        self.store.save_vote(self.term, &self.voted_for)
+    }
+
+    // Follow in the given term. The vote is kept within the same term.
+    fn step_down(&mut self, term: u64) -> Result<(), String> {
+        if self.role == Role::Leader {
+            metrics::cluster_leader(false);
+        }
+        self.role = Role::Follower;
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            return self.save_vote();
+        }
+        Ok(())
+    }
+
+    // Drop the entries from index on, they conflict with the leader's log.
+    fn truncate(&mut self, index: u64) {
+        let keep = index.saturating_sub(self.base.0 + 1) as usize;
+        self.entries.truncate(keep);
+        self.waiting.retain(|i, _| *i < index);
+    }
+}
+
+pub struct Node {
+    id: String,         // cluster address, also the node id
+    address: String,    // server address of this node
+    peers: Vec<(String, ClusterClient<Channel>)>,
+    heartbeat: Duration,
+    machine: Arc<dyn StateMachine>,
+    raft: Mutex<Raft>,
+    proposed: Notify,   // wakes the leader to send new entries
+    stopped: AtomicBool,
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Node {
+    // Load the raft state kept in dir and restore the snapshot into the state
+    // machine. Entries after the snapshot are applied once they are known
+    // committed.
+    pub fn new(id: String, address: String, peers: Vec<String>, heartbeat: Duration, dir: &Path,
+               machine: Arc<dyn StateMachine>) -> Result<Arc<Node>, String> {
+        let mut clients = Vec::new();
+        for peer in peers {
+            let channel = Channel::from_shared(format!("http://{}", peer))
+                .map_err(|e| format!("bad cluster peer {}: {}", peer, e))?
+                .connect_lazy();
+            clients.push((peer, ClusterClient::new(channel)));
+        }
+        let (store, vote, snapshot, entries) = Store::open(dir)?;
+        let base = match snapshot {
+            Some(s) => {
+                machine.restore(&s.state);
+                (s.last_index, s.last_term)
+            },
+            None => (0, 0),
+        };
+        let node = Node {
+            id,
+            address,
+            peers: clients,
+            heartbeat,
+            machine,
+            raft: Mutex::new(Raft {
+                role: Role::Follower,
+                term: vote.term,
+                voted_for: vote.voted_for,
+                leader: String::new(),
+                base,
+                entries,
+                commit: base.0,
+                applied: base.0,
+                next: HashMap::new(),
+                matched: HashMap::new(),
+                waiting: HashMap::new(),
+                heard: Instant::now(),
+                timeout: Duration::ZERO,
+                store,
+            }),
+            proposed: Notify::new(),
+            stopped: AtomicBool::new(false),
+            tasks: Mutex::new(Vec::new()),
+        };
+        node.raft.lock().unwrap().timeout = node.election_timeout();
+        Ok(Arc::new(node))
+    }
+
+    // Serve the cluster RPCs on the node id and start taking part in elections,
+    // with a task per peer that replicates to it while this node leads.
+    pub fn start(self: &Arc<Self>) -> Result<(), String> {
+        let addr = self.id.parse()
+            .map_err(|e| format!("bad cluster address {}: {}", self.id, e))?;
+        let service = ClusterServer::new(ClusterService(self.clone()));
+        let server = tokio::spawn(async move {
+            if let Err(e) = Server::builder().add_service(service).serve(addr).await {
+                error!(error = %e, "cluster listener failed");
+            }
+        });
+        let run = tokio::spawn(self.clone().run());
+        self.stopped.store(false, Ordering::Relaxed);
+        let mut tasks = self.tasks.lock().unwrap();
+        tasks.extend([server, run]);
+        for peer in 0..self.peers.len() {
+            tasks.push(tokio::spawn(self.clone().replicate(peer)));
+        }
+        Ok(())
+    }
+
+    // Stop serving and taking part, the node looks failed to its peers.
+    // Connections already open outlive the listener, so they are refused too.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.raft.lock().unwrap().role
+    }
+
+    // Server address of the current leader, empty when none is known.
+    pub fn leader(&self) -> String {
+        self.raft.lock().unwrap().leader.clone()
+    }
+
+    // Append a command to the log of the leader. Returns the receiver of its
+    // result, sent once the command is committed and applied.
+    fn propose(&self, command: Vec<u8>) -> Result<oneshot::Receiver<Vec<u8>>, String> {
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return Err("not the cluster leader".to_string());
+            }
+            let entry = LogEntry { index: raft.last().0 + 1, term: raft.term, command, state: false };
+            raft.store.append(std::slice::from_ref(&entry))?;
+            let (index, term) = (entry.index, entry.term);
+            raft.entries.push(entry);
+            raft.waiting.insert(index, (term, tx));
+            // commits at once in a cluster of one
+            self.advance(&mut raft);
+        }
+        self.proposed.notify_waiters();
+        Ok(rx)
+    }
+
+    // Run a command through the log and wait for its result.
+    pub async fn write(&self, command: Vec<u8>, timeout: Duration) -> Result<Vec<u8>, String> {
+        let result = self.propose(command)?;
+        match tokio::time::timeout(timeout, result).await {
+            Ok(Ok(r)) => Ok(r),
+            Ok(Err(_)) => Err("write lost in a change of cluster leader".to_string()),
+            Err(_) => Err("write not committed by a majority of the cluster in time, it may still be applied"
+                .to_string()),
+        }
+    }
+
+    fn election_timeout(&self) -> Duration {
+        self.heartbeat * rand::thread_rng().gen_range(5..=10)
+    }
+
+    // Well under the shortest election timeout, so a peer that does not answer
+    // costs its own replication task a little and the followers that do answer
+    // keep hearing from the leader.
+    fn rpc_timeout(&self) -> Duration {
+        self.heartbeat * 2
+    }
+
+    // Snapshots hold the whole registry and may take longer to send.
+    fn snapshot_timeout(&self) -> Duration {
+        (self.heartbeat * 20).max(Duration::from_secs(5))
+    }
+
+    fn majority(&self, acks: usize) -> bool {
+        acks * 2 > self.peers.len() + 1
+    }
+
+    // Call an election when no leader was heard from within the timeout.
+    async fn run(self: Arc<Self>) {
+        let mut ticker = tokio::time::interval(self.heartbeat);
+        loop {
+            ticker.tick().await;
+            let due = {
+                let raft = self.raft.lock().unwrap();
+                raft.role != Role::Leader && raft.heard.elapsed() >= raft.timeout
+            };
+            if due {
+                self.elect().await;
+            }
+        }
+    }
+
+    // Ask the peers for their votes in a new term and lead when a majority
+    // grants them. The term and the vote for itself are written first.
+    async fn elect(&self) {
+        let request = {
+            let mut raft = self.raft.lock().unwrap();
+            raft.term += 1;
+            raft.role = Role::Candidate;
+            raft.voted_for = Some(self.id.clone());
+            raft.leader.clear();
+            raft.heard = Instant::now();
+            raft.timeout = self.election_timeout();
+            if let Err(e) = raft.save_vote() {
+                error!(error = %e, "failed to keep the cluster vote");
+                raft.role = Role::Follower;
+                return;
+            }
+            let (last_log_index, last_log_term) = raft.last();
+            VoteRequest {
+                term: raft.term,
+                candidate: self.id.clone(),
+                last_log_index,
+                last_log_term,
+            }
+        };
+        let mut calls = JoinSet::new();
+        for (_, client) in self.peers.iter() {
+            let mut client = client.clone();
+            let request = request.clone();
+            let timeout = self.rpc_timeout();
+            calls.spawn(async move { tokio::time::timeout(timeout, client.vote(request)).await });
+        }
+        // the calls still out are dropped once a majority voted
+        let mut votes = 1;
+        while !self.majority(votes) && let Some(joined) = calls.join_next().await {
+            if let Ok(Ok(Ok(response))) = joined {
+                let response = response.into_inner();
+                if response.term > request.term {
+                    let _ = self.raft.lock().unwrap().step_down(response.term);
+                    return;
+                }
+                if response.granted {
+                    votes += 1;
+                }
+            }
+        }
+
+        {
+            let mut raft = self.raft.lock().unwrap();
+            if raft.role != Role::Candidate || raft.term != request.term || !self.majority(votes) {
+                return;
+            }
+            // The term opens with an entry of its own, committing it commits
+            // the entries of earlier terms. The first leader of a new cluster
+            // puts its registry state there for the others to start from.
+            let (last, _) = raft.last();
+            let entry = match last {
+                0 => LogEntry { index: 1, term: raft.term, command: self.machine.snapshot(), state: true },
+                _ => LogEntry { index: last + 1, term: raft.term, command: Vec::new(), state: false },
+            };
+            if let Err(e) = raft.store.append(std::slice::from_ref(&entry)) {
+                error!(error = %e, "failed to open the cluster term");
+                raft.role = Role::Follower;
+                return;
+            }
+            raft.entries.push(entry);
+            raft.role = Role::Leader;
+            raft.leader = self.address.clone();
+            raft.next = self.peers.iter().map(|(p, _)| (p.clone(), last + 1)).collect();
+            raft.matched = self.peers.iter().map(|(p, _)| (p.clone(), 0)).collect();
+            metrics::cluster_leader(true);
+            info!(term = raft.term, node = %self.id, "elected cluster leader");
+            self.advance(&mut raft);
+        }
+        // the replication tasks announce the new leader right away
+        self.proposed.notify_waiters();
+    }
+
+    // Keep one peer up to date while this node leads. Entries it is missing go
+    // out as soon as they are proposed, a heartbeat when it holds them all. A
+    // peer that is slow or does not answer holds up no other, and a write
+    // commits as soon as a majority holds it.
+    async fn replicate(self: Arc<Self>, peer: usize) {
+        let (id, client) = &self.peers[peer];
+        loop {
+            let proposed = self.proposed.notified();
+            tokio::pin!(proposed);
+            proposed.as_mut().enable();
+            let sent = Instant::now();
+            if self.send(id, client.clone()).await {
+                continue;
+            }
+            tokio::select! {
+                _ = proposed => {},
+                _ = tokio::time::sleep_until((sent + self.heartbeat).into()) => {},
+            }
+        }
+    }
+
+    // Send a peer the entries it is missing, or the snapshot when the entries
+    // it needs were compacted. Returns true when the peer answered and is
+    // still missing entries.
+    async fn send(&self, peer: &str, mut client: ClusterClient<Channel>) -> bool {
+        type Pending = std::pin::Pin<Box<dyn Future<Output = Option<AppendResponse>> + Send>>;
+        let (term, response) = {
+            let raft = self.raft.lock().unwrap();
+            if raft.role != Role::Leader {
+                return false;
+            }
+            let next = raft.next.get(peer).copied().unwrap_or(1).max(1);
+            if next <= raft.base.0 {
+                let Some(snapshot) = raft.store.load_snapshot() else {
+                    return false;
+                };
+                let last_index = snapshot.last_index;
+                let request = SnapshotRequest {
+                    term: raft.term,
+                    leader: self.id.clone(),
+                    leader_address: self.address.clone(),
+                    snapshot: Some(snapshot),
+                };
+                let timeout = self.snapshot_timeout();
+                let response = async move {
+                    let response = tokio::time::timeout(timeout, client.snapshot(request)).await;
+                    response.ok().and_then(|r| r.ok()).map(|r| {
+                        AppendResponse { term: r.into_inner().term, success: true, match_index: last_index }
+                    })
+                };
+                (raft.term, Box::pin(response) as Pending)
+            } else {
+                let prev_log_index = next - 1;
+                let from = (next - raft.base.0 - 1) as usize;
+                let request = AppendRequest {
+                    term: raft.term,
+                    leader: self.id.clone(),
+                    leader_address: self.address.clone(),
+                    prev_log_index,
+                    prev_log_term: raft.term_at(prev_log_index).unwrap_or(0),
+                    entries: raft.entries.iter().skip(from).take(MAX_APPEND).cloned().collect(),
+                    commit_index: raft.commit,
+                };
+                let timeout = self.rpc_timeout();
+                let response = async move {
+                    let response = tokio::time::timeout(timeout, client.append(request)).await;
+                    response.ok().and_then(|r| r.ok()).map(|r| r.into_inner())
+                };
+                (raft.term, Box::pin(response) as Pending)
+            }
+        };
+        let Some(response) = response.await else {
+            return false;
+        };
+        let mut raft = self.raft.lock().unwrap();
+        if response.term > raft.term {
+            info!(term = response.term, "cluster leader stepping down");
+            let _ = raft.step_down(response.term);
+            return false;
+        }
+        if raft.role != Role::Leader || raft.term != term {
+            return false;
+        }
+        let next = raft.next.get(peer).copied().unwrap_or(1);
+        if response.success {
+            let matched = raft.matched.get(peer).copied().unwrap_or(0).max(response.match_index);
+            raft.matched.insert(peer.to_string(), matched);
+            raft.next.insert(peer.to_string(), matched + 1);
+            self.advance(&mut raft);
+        } else {
+            // back off to the last entry the peer may hold
+            raft.next.insert(peer.to_string(), (response.match_index + 1).min(next.saturating_sub(1)).max(1));
+        }
+        raft.next.get(peer).is_some_and(|n| *n <= raft.last().0)
+    }
+
+    // Commit the last entry of the current term a majority holds, and with it
+    // the entries before it, then apply them.
+    fn advance(&self, raft: &mut Raft) {
+        if raft.role != Role::Leader {
+            return;
+        }
+        let (last, _) = raft.last();
+        for index in (raft.commit + 1..=last).rev() {
+            let acks = 1 + raft.matched.values().filter(|m| **m >= index).count();
+            if raft.term_at(index) == Some(raft.term) && self.majority(acks) {
+                raft.commit = index;
+                break;
+            }
+        }
+        self.apply_committed(raft);
+    }
+
+    // Apply the committed entries in order and hand the results to the
+    // writes waiting for them.
+    fn apply_committed(&self, raft: &mut Raft) {
+        while raft.applied < raft.commit {
+            let index = raft.applied + 1;
+            let Some(entry) = raft.entry(index).cloned() else {
+                break;
+            };
+            let waiting = raft.waiting.remove(&index);
+            let result = match (entry.state, entry.command.is_empty()) {
+                (true, _) => {
+                    self.machine.restore(&entry.command);
+                    Vec::new()
+                },
+                (false, true) => Vec::new(),
+                (false, false) => self.machine.apply(&entry.command, waiting.is_some()),
+            };
+            raft.applied = index;
+            if let Some((term, result_tx)) = waiting
+                && term == entry.term {
+                let _ = result_tx.send(result);
+            }
+        }
+        self.compact(raft);
+    }
+
+    // Replace the applied entries with a snapshot once the log grows long.
+    fn compact(&self, raft: &mut Raft) {
+        if raft.entries.len() < SNAPSHOT_ENTRIES || raft.applied <= raft.base.0 {
+            return;
+        }
+        let last_index = raft.applied;
+        let last_term = raft.term_at(last_index).unwrap_or(0);
+        let snapshot = Snapshot { last_index, last_term, state: self.machine.snapshot() };
+        let mut entries = raft.entries.clone();
+        entries.retain(|e| e.index > last_index);
+        let saved = raft.store.save_snapshot(&snapshot).and_then(|_| raft.store.rewrite(&entries));
+        match saved {
+            Ok(()) => {
+                raft.entries = entries;
+                raft.base = (last_index, last_term);
+            },
+            Err(e) => error!(error = %e, "failed to compact the cluster log"),
+        }
+    }
+
+    fn vote(&self, request: VoteRequest) -> VoteResponse {
+        let mut raft = self.raft.lock().unwrap();
+        if request.term > raft.term && let Err(e) = raft.step_down(request.term) {
+            error!(error = %e, "failed to keep the cluster term");
+            return VoteResponse { term: raft.term, granted: false };
+        }
+        // only candidates holding at least our entries can lead
+        let (last_index, last_term) = raft.last();
+        let current = (request.last_log_term, request.last_log_index) >= (last_term, last_index);
+        let granted = request.term == raft.term && current
+            && raft.voted_for.as_ref().is_none_or(|v| *v == request.candidate);
+        if !granted {
+            return VoteResponse { term: raft.term, granted };
+        }
+        let previous = raft.voted_for.replace(request.candidate);
+        if let Err(e) = raft.save_vote() {
+            error!(error = %e, "failed to keep the cluster vote");
+            raft.voted_for = previous;
+            return VoteResponse { term: raft.term, granted: false };
+        }
+        raft.heard = Instant::now();
+        raft.timeout = self.election_timeout();
+        VoteResponse { term: raft.term, granted }
+    }
+
+    fn append(&self, request: AppendRequest) -> AppendResponse {
+        let mut guard = self.raft.lock().unwrap();
+        let raft = &mut *guard;
+        let fail = |raft: &Raft, match_index: u64| AppendResponse { term: raft.term, success: false, match_index };
+        if request.term < raft.term {
+            return fail(raft, 0);
+        }
+        if (request.term > raft.term || raft.role != Role::Follower)
+            && let Err(e) = raft.step_down(request.term) {
+            error!(error = %e, "failed to keep the cluster term");
+            return fail(raft, 0);
+        }
+        raft.heard = Instant::now();
+        raft.timeout = self.election_timeout();
+        if raft.leader != request.leader_address {
+            info!(term = raft.term, leader = %request.leader, "following cluster leader");
+            raft.leader = request.leader_address;
+        }
+        // the new entries must follow an entry held with the same term
+        let prev = request.prev_log_index;
+        let (last, _) = raft.last();
+        if prev > last {
+            return fail(raft, last);
+        }
+        if let Some(term) = raft.term_at(prev)
+            && term != request.prev_log_term {
+            return fail(raft, prev - 1);
+        }
+        let matched = request.entries.last().map(|e| e.index).unwrap_or(prev).max(prev);
+        let mut added = Vec::new();
+        let mut truncated = false;
+        for entry in request.entries {
+            if entry.index <= raft.base.0 {
+                continue;
+            }
+            match raft.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    raft.truncate(entry.index);
+                    truncated = true;
+                },
+                None => {},
+            }
+            added.push(entry.clone());
+            raft.entries.push(entry);
+        }
+        let saved = match truncated {
+            true => raft.store.rewrite(&raft.entries),
+            false if !added.is_empty() => raft.store.append(&added),
+            false => Ok(()),
+        };
+        if let Err(e) = saved {
+            error!(error = %e, "failed to keep cluster log entries");
+            return fail(raft, prev);
+        }
+        if request.commit_index > raft.commit {
+            raft.commit = request.commit_index.min(matched).max(raft.commit);
+        }
+        self.apply_committed(raft);
+        AppendResponse { term: raft.term, success: true, match_index: matched }
+    }
+
+    // Start over from the leader's snapshot. Entries following it are kept.
+    fn install(&self, request: SnapshotRequest) -> SnapshotResponse {
+        let mut raft = self.raft.lock().unwrap();
+        if request.term < raft.term {
+            return SnapshotResponse { term: raft.term };
+        }
+        if (request.term > raft.term || raft.role != Role::Follower)
+            && let Err(e) = raft.step_down(request.term) {
+            error!(error = %e, "failed to keep the cluster term");
+            return SnapshotResponse { term: raft.term };
+        }
+        raft.heard = Instant::now();
+        raft.timeout = self.election_timeout();
+        raft.leader = request.leader_address;
+        let Some(snapshot) = request.snapshot else {
+            return SnapshotResponse { term: raft.term };
+        };
+        if snapshot.last_index <= raft.commit {
+            return SnapshotResponse { term: raft.term };
+        }
+        let (last_index, last_term) = (snapshot.last_index, snapshot.last_term);
+        let mut entries = match raft.term_at(last_index) == Some(last_term) {
+            true => raft.entries.clone(),
+            false => Vec::new(),
+        };
+        entries.retain(|e| e.index > last_index);
+        let saved = raft.store.save_snapshot(&snapshot).and_then(|_| raft.store.rewrite(&entries));
+        if let Err(e) = saved {
+            error!(error = %e, "failed to keep the cluster snapshot");
+            return SnapshotResponse { term: raft.term };
+        }
+        self.machine.restore(&snapshot.state);
+        info!(index = last_index, "installed cluster snapshot");
+        raft.entries = entries;
+        raft.base = (last_index, last_term);
+        raft.commit = last_index;
+        raft.applied = last_index;
+        raft.waiting.retain(|i, _| *i > last_index);
+        SnapshotResponse { term: raft.term }
+    }
+}
+
+struct ClusterService(Arc<Node>);
+
+#[tonic::async_trait]
+impl Cluster for ClusterService {
+    async fn vote(&self, request: Request<VoteRequest>) -> Result<Response<VoteResponse>, Status> {
+        self.running()?;
+        Ok(Response::new(self.0.vote(request.into_inner())))
+    }
+
+    async fn append(&self, request: Request<AppendRequest>) -> Result<Response<AppendResponse>, Status> {
+        self.running()?;
+        Ok(Response::new(self.0.append(request.into_inner())))
+    }
+
+    async fn snapshot(&self, request: Request<SnapshotRequest>) -> Result<Response<SnapshotResponse>, Status> {
+        self.running()?;
+        Ok(Response::new(self.0.install(request.into_inner())))
+    }
+}
+
+impl ClusterService {
+    #[allow(clippy::result_large_err)]
+    fn running(&self) -> Result<(), Status> {
+        match self.0.stopped.load(Ordering::Relaxed) {
+            true => Err(Status::unavailable("cluster node stopped")),
+            false => Ok(()),
+        }
+    }
+}
+
+// Join the cluster when cluster_address is configured. The node id is its
+// cluster address, cluster_peers lists the cluster addresses of the others.
+pub fn init(params: &HashMap<String, String>) -> Result<(), String> {
+    let Some(id) = params.get("cluster_address") else {
+        return Ok(());
+    };
+    let peers: Vec<String> = params.get("cluster_peers").map(|p| p.as_str()).unwrap_or("")
+        .split(',').map(|p| p.trim().to_string()).filter(|p| !p.is_empty()).collect();
+    if peers.is_empty() {
+        warn!("cluster_peers is empty, running a single node cluster");
+    }
+    let heartbeat = Duration::from_millis(config_number(params, "cluster_heartbeat_ms", 100).max(1));
+    let commit = Duration::from_secs(config_number(params, "cluster_commit_timeout", 2));
+    let address = params.get("server_address").cloned().unwrap_or_default();
+    let dir = params.get("cluster_dir").map(|d| d.as_str()).unwrap_or("cluster");
+    let node = Node::new(id.clone(), address, peers.clone(), heartbeat, Path::new(dir), Arc::new(Registry))?;
+    node.start()?;
+    info!(address = %id, peers = ?peers, dir, "joined registry cluster");
+    let _ = COMMIT_TIMEOUT.set(commit);
+    let _ = NODE.set(node);
+    tokio::spawn(send_keepalives(heartbeat));
+    Ok(())
+}
+
+// True when this registry is part of a cluster.
+pub fn enabled() -> bool {
+    NODE.get().is_some()
+}
+
+// True when this registry takes writes, always without a cluster.
+pub fn is_leader() -> bool {
+    NODE.get().is_none_or(|node| node.role() == Role::Leader)
+}
+
+// Writes to a follower are refused with the leader's server address in the
+// message and in the registry-leader metadata.
+#[allow(clippy::result_large_err)]
+pub fn check_leader() -> Result<(), Status> {
+    let Some(node) = NODE.get() else {
+        return Ok(());
+    };
+    if node.role() == Role::Leader {
+        return Ok(());
+    }
+    let leader = node.leader();
+    if leader.is_empty() {
+        return Err(Status::unavailable("no cluster leader elected"));
+    }
+    let mut status = Status::unavailable(format!("not the cluster leader, send writes to {}", leader));
+    if let Ok(value) = leader.parse() {
+        status.metadata_mut().insert("registry-leader", value);
+    }
+    Err(status)
+}
+
+// Run a registry write through the cluster log. Returns the encoded response
+// of its handler once a majority of the nodes holds the write and it is
+// applied. A write that times out may still be applied later.
+#[allow(clippy::result_large_err)]
+pub async fn write(method: &str, request: Vec<u8>, peer: Option<SocketAddr>) -> Result<Vec<u8>, Status> {
+    let Some(node) = NODE.get() else {
+        return Err(Status::internal("not part of a cluster"));
+    };
+    let command = Command {
+        method: method.to_string(),
+        request,
+        time: UNIX_EPOCH.elapsed().map(|d| d.as_millis() as u64).unwrap_or(0),
+        seed: rand::random(),
+        peer: peer.map(|p| p.to_string()).unwrap_or_default(),
+    };
+    let timeout = COMMIT_TIMEOUT.get().copied().unwrap_or(Duration::from_secs(2));
+    node.write(command.encode_to_vec(), timeout).await.map_err(Status::unavailable)
+}
+
+// Take a keepalive on the leader. It goes into the log with the next batch.
+pub fn keep_alive(update: KeepaliveUpdate) {
+    let key = (update.namespace.clone(), update.protobuf_name.clone(), update.instance_id.clone());
+    KEEPALIVES.lock().unwrap().insert(key, update);
+}
+
+// Put the keepalives taken since the last batch into the log once per heartbeat.
+async fn send_keepalives(heartbeat: Duration) {
+    let mut ticker = tokio::time::interval(heartbeat);
+    loop {
+        ticker.tick().await;
+        let updates: Vec<KeepaliveUpdate> = KEEPALIVES.lock().unwrap().drain().map(|(_, u)| u).collect();
+        if updates.is_empty() || !is_leader() {
+            continue;
+        }
+        let batch = KeepaliveBatch { updates };
+        if let Err(e) = write("keepalives", batch.encode_to_vec(), None).await {
+            warn!(error = %e.message(), "keepalives not replicated");
+        }
+    }
+}
+#[cfg(test)]
+#[derive(Default)]
+struct TestMachine(Mutex<Vec<u8>>);
+
+#[cfg(test)]
+impl StateMachine for TestMachine {
+    fn apply(&self, command: &[u8], _local: bool) -> Vec<u8> {
+        *self.0.lock().unwrap() = command.to_vec();
+        [b"applied ", command].concat()
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn restore(&self, state: &[u8]) {
+        *self.0.lock().unwrap() = state.to_vec();
+    }
+}
+
+// Empty raft directory for a test.
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("registry-cluster-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+type TestNode = (Arc<Node>, Arc<TestMachine>);
+
+// Start a cluster of three nodes on free localhost ports.
+#[cfg(test)]
+fn test_cluster(name: &str, silent: &[String]) -> (Vec<String>, Vec<TestNode>) {
+    let ids: Vec<String> = (0..3 - silent.len()).map(|_| {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }).collect();
+    let mut nodes = Vec::new();
+    for (i, id) in ids.iter().enumerate() {
+        let peers = ids.iter().chain(silent).filter(|p| *p != id).cloned().collect();
+        let machine = Arc::new(TestMachine::default());
+        let dir = test_dir(&format!("{}-{}", name, i));
+        let node = Node::new(id.clone(), format!("server-{}", id), peers, Duration::from_millis(50),
+                             &dir, machine.clone()).unwrap();
+        node.start().unwrap();
+        nodes.push((node, machine));
+    }
+    (ids, nodes)
+}
+
+#[cfg(test)]
+async fn leader_of(nodes: &[&(Arc<Node>, Arc<TestMachine>)]) -> usize {
+    for _ in 0..100 {
+        let leaders: Vec<usize> = (0..nodes.len()).filter(|i| nodes[*i].0.role() == Role::Leader).collect();
+        if leaders.len() == 1 {
+            return leaders[0];
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("no cluster leader elected");
+}
+
+#[tokio::test]
+async fn test_cluster_replicates_and_fails_over() {
+    async fn holds(machine: &TestMachine, state: &[u8]) -> bool {
+        for _ in 0..100 {
+            if machine.snapshot() == state {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    let (ids, nodes) = test_cluster("failover", &[]);
+    let all: Vec<_> = nodes.iter().collect();
+    let first = leader_of(&all).await;
+    let (leader, machine) = &nodes[first];
+    let result = leader.write(b"one".to_vec(), Duration::from_secs(2)).await.unwrap();
+    assert_eq!(result, b"applied one");
+    assert_eq!(machine.snapshot(), b"one");
+    for (i, (node, machine)) in nodes.iter().enumerate() {
+        if i != first {
+            assert_eq!(node.role(), Role::Follower);
+            assert!(holds(machine, b"one").await, "follower missed the write");
+            assert_eq!(node.leader(), format!("server-{}", ids[first]));
+        }
+    }
+
+    // the two remaining nodes elect a new leader that keeps taking writes
+    leader.stop();
+    let rest: Vec<_> = nodes.iter().enumerate().filter(|(i, _)| *i != first).map(|(_, n)| n).collect();
+    let second = leader_of(&rest).await;
+    let (leader, machine) = rest[second];
+    assert_eq!(machine.snapshot(), b"one");
+    leader.write(b"two".to_vec(), Duration::from_secs(2)).await.unwrap();
+    assert!(holds(&rest[1 - second].1, b"two").await, "follower missed the write");
+    for (node, _) in nodes.iter() {
+        node.stop();
+    }
+}
+
+#[tokio::test]
+async fn test_write_applied_only_when_committed() {
+    let (_, nodes) = test_cluster("commit", &[]);
+    let all: Vec<_> = nodes.iter().collect();
+    let first = leader_of(&all).await;
+    let (leader, machine) = &nodes[first];
+    leader.write(b"one".to_vec(), Duration::from_secs(2)).await.unwrap();
+    // without its followers the leader cannot commit
+    for (i, (node, _)) in nodes.iter().enumerate() {
+        if i != first {
+            node.stop();
+        }
+    }
+    let written = leader.write(b"two".to_vec(), Duration::from_millis(500)).await;
+    assert!(written.is_err(), "write committed without a majority");
+    assert_eq!(machine.snapshot(), b"one", "uncommitted write applied");
+    leader.stop();
+}
+
+#[tokio::test]
+async fn test_silent_peer_holds_up_nothing() {
+    // a member that takes connections and never answers
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let silent = listener.local_addr().unwrap().to_string();
+    std::thread::spawn(move || {
+        let mut held = Vec::new();
+        for stream in listener.incoming() {
+            held.push(stream);
+        }
+    });
+    let (_, nodes) = test_cluster("silent", &[silent]);
+    let all: Vec<_> = nodes.iter().collect();
+    let first = leader_of(&all).await;
+    let (leader, machine) = &nodes[first];
+    let term = leader.raft.lock().unwrap().term;
+    // each write commits with the one follower that answers, without waiting
+    // out the silent member
+    let started = Instant::now();
+    for i in 0..10u8 {
+        leader.write(vec![i], Duration::from_secs(2)).await.unwrap();
+    }
+    assert!(started.elapsed() < leader.rpc_timeout() * 10, "writes waited on the silent member");
+    assert_eq!(machine.snapshot(), [9]);
+    // the follower keeps hearing from the leader and calls no election
+    tokio::time::sleep(leader.heartbeat * 20).await;
+    assert_eq!(leader.role(), Role::Leader);
+    assert_eq!(leader.raft.lock().unwrap().term, term);
+    for (node, _) in &nodes {
+        node.stop();
+    }
+}
+
+#[test]
+fn test_store_keeps_vote_and_log() {
+    let dir = test_dir("store");
+    let entry = |index: u64, term: u64| LogEntry { index, term, command: vec![index as u8], state: false };
+    {
+        let (mut store, vote, snapshot, entries) = Store::open(&dir).unwrap();
+        assert_eq!((vote.term, vote.voted_for, snapshot, entries.len()), (0, None, None, 0));
+        store.save_vote(3, &Some("node1".to_string())).unwrap();
+        store.append(&[entry(1, 1), entry(2, 1)]).unwrap();
+        store.append(&[entry(3, 2)]).unwrap();
+    }
+    // a partial entry from a crash in the middle of an append is dropped
+    let mut log = OpenOptions::new().append(true).open(dir.join("log")).unwrap();
+    log.write_all(&[20, 1, 2]).unwrap();
+    drop(log);
+    {
+        let (mut store, vote, _, entries) = Store::open(&dir).unwrap();
+        assert_eq!((vote.term, vote.voted_for.as_deref()), (3, Some("node1")));
+        assert_eq!(entries, vec![entry(1, 1), entry(2, 1), entry(3, 2)]);
+        store.append(&[entry(4, 3)]).unwrap();
+        store.save_snapshot(&Snapshot { last_index: 2, last_term: 1, state: b"state".to_vec() }).unwrap();
+    }
+    let (_, _, snapshot, entries) = Store::open(&dir).unwrap();
+    assert_eq!(snapshot.unwrap().state, b"state");
+    assert_eq!(entries, vec![entry(3, 2), entry(4, 3)], "entries covered by the snapshot kept");
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_restarted_node_keeps_its_vote() {
+    let dir = test_dir("vote");
+    let new_node = || Node::new("127.0.0.1:1".to_string(), String::new(), vec!["127.0.0.1:2".to_string()],
+                                Duration::from_millis(50), &dir, Arc::new(TestMachine::default())).unwrap();
+    let vote = |candidate: &str| VoteRequest {
+        term: 5, candidate: candidate.to_string(), last_log_index: 0, last_log_term: 0,
+    };
+    assert!(new_node().vote(vote("a")).granted);
+    let restarted = new_node();
+    let response = restarted.vote(vote("b"));
+    assert_eq!(response.term, 5);
+    assert!(!response.granted, "restarted node voted twice in a term");
+    assert!(restarted.vote(vote("a")).granted);
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -14,10 +14,14 @@
  * limitations under the License.
  *
  */
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::sync::Mutex;
-use jwt_simple::prelude::{Duration, RS256KeyPair};
-use crate::{jwt, Protobuf, Protobufs, Service, KPAIR};
+use std::time::{SystemTime, UNIX_EPOCH};
+use jwt_simple::prelude::{Duration, JWTClaims, RS256KeyPair};
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use crate::{audit, jwt, limits, metrics, Protobuf, Protobufs, Service, KPAIR, SETTINGS};
 use crate::registry;
 
 ///////////////////////////////////////////////////////////////////////////////////////////
@@ -29,14 +33,15 @@ use crate::registry;
 ///////////////////////////////////////////////////////////////////////////////////////////
 ///////////////////////////////////////////////////////////////////////////////////////////
 
-pub fn make_token(user: String, subject: String, isadmin: bool, duration: Duration) -> Option<String> {
+pub fn make_token(user: String, subject: String, instance_id: String, namespace: String, isadmin: bool,
+                  duration: Duration) -> Option<String> {
     let kpo = get_keypair();
-    match jwt::create_token(kpo.unwrap(), user.to_string(), subject.to_string(), isadmin,
-                            duration) {
+    match jwt::create_token(kpo.unwrap(), user.to_string(), subject.to_string(), instance_id,
+                            namespace, isadmin, duration) {
                            // Duration::from_hours(12)) {
         Ok(jwttoken) => { return Some(jwttoken); },
         Err(e) => {
-            println!("make token error: {}", e);
+            tracing::error!(error = %e, "make token error");
         },
     };
     return None;
@@ -55,6 +60,128 @@ pub fn get_keypair() -> Option<&'static RS256KeyPair> {
     return KPAIR.get();
 }
 
+// Validate a token presented to an RPC. Failures are counted per method and
+// audited, the token subject is recorded on the RPC span. A valid token is
+// then checked against the rate limit of its subject.
+pub fn check_token(method: &str, token: String) -> Result<JWTClaims<jwt::MyAdditionalData>, String> {
+    let kp = get_keypair();
+    let claim = jwt::validate_token(kp.unwrap(), token);
+    match &claim {
+        Ok(c) => {
+            let span = tracing::Span::current();
+            if let Some(subject) = &c.subject {
+                span.record("subject", subject.as_str());
+            }
+            span.record("user", c.custom.user_name.as_str());
+            limits::check_subject(method, c)?;
+        },
+        Err(e) => {
+            metrics::token_failure(method);
+            tracing::warn!(method, error = %e, "token validation failed");
+            audit::record(audit::AuditEvent {
+                event: "token_rejected",
+                method: method.to_string(),
+                reason: e.clone(),
+                ..Default::default()
+            });
+        },
+    }
+    claim
+}
+
+// Validate a token presented to an RPC naming a namespace. A token is only
+// good in the namespace it was issued for, an admin token issued for all
+// namespaces is good in any of them.
+pub fn check_scoped_token(method: &str, token: String, namespace: &str)
+    -> Result<JWTClaims<jwt::MyAdditionalData>, String> {
+    let claim = check_token(method, token)?;
+    check_token_namespace(method, &claim, namespace)?;
+    Ok(claim)
+}
+
+// Check that a validated token is good in the namespace a request names.
+pub fn check_token_namespace(method: &str, claim: &JWTClaims<jwt::MyAdditionalData>, namespace: &str)
+    -> Result<(), String> {
+    let ns = namespace_name(namespace);
+    let token_ns = namespace_name(&claim.custom.namespace);
+    if token_ns == ns || (claim.custom.user_is_admin && token_ns == ALL_NAMESPACES) {
+        return Ok(());
+    }
+    let msg = format!("token is not valid in namespace {}", ns);
+    metrics::token_failure(method);
+    tracing::warn!(method, namespace = %ns, token_namespace = %token_ns, "token used outside its namespace");
+    audit::record(audit::AuditEvent {
+        event: "token_rejected",
+        namespace: ns,
+        method: method.to_string(),
+        reason: msg.clone(),
+        ..Default::default()
+    });
+    Err(msg)
+}
+
+// Namespace used when a request or token names none.
+pub const DEFAULT_NAMESPACE: &str = "default";
+// Namespace of an admin token good in every namespace.
+pub const ALL_NAMESPACES: &str = "*";
+
+// The namespace a request names, the default namespace when it is empty.
+pub fn namespace_name(namespace: &str) -> String {
+    match namespace.is_empty() {
+        true => DEFAULT_NAMESPACE.to_string(),
+        false => namespace.to_string(),
+    }
+}
+
+// Check a namespace a token is issued for. Names are kept to letters, digits,
+// '-', '_' and '.' so they are safe in tokens, metric labels and exports.
+pub fn check_namespace(namespace: &str) -> Result<String, String> {
+    let ns = namespace_name(namespace);
+    let valid = ns.len() <= 63
+        && ns.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.');
+    match valid {
+        true => Ok(ns),
+        false => Err(format!("invalid namespace '{}'", namespace)),
+    }
+}
+
+// Most instances a namespace may register, 0 for no limit. namespace_quotas
+// sets the quota of single namespaces as "dev=20,prod=500", the others get
+// namespace_max_instances.
+pub fn instance_quota(namespace: &str) -> usize {
+    let quotas = setting("namespace_quotas").unwrap_or_default();
+    parse_quota(&quotas, namespace)
+        .or_else(|| setting("namespace_max_instances").and_then(|v| v.parse().ok()))
+        .unwrap_or(0)
+}
+
+fn parse_quota(quotas: &str, namespace: &str) -> Option<usize> {
+    quotas.split(',')
+        .filter_map(|q| q.split_once('='))
+        .find(|(ns, _)| ns.trim() == namespace)
+        .and_then(|(_, v)| v.trim().parse().ok())
+}
+
+#[test]
+fn test_namespaces() {
+    assert_eq!(namespace_name(""), "default");
+    assert_eq!(check_namespace("prod-eu.1"), Ok("prod-eu.1".to_string()));
+    assert!(check_namespace("a/b").is_err());
+    assert!(check_namespace("*").is_err(), "all namespaces accepted as a name");
+    assert_eq!(parse_quota("dev=20, prod = 500", "prod"), Some(500));
+    assert_eq!(parse_quota("dev=20", "prod"), None);
+    assert_eq!(parse_quota("", "dev"), None);
+}
+
+// Look up a configuration value loaded from setting.toml. Returns None when the
+// key is not set or the configuration was never loaded, as in tests.
+pub fn setting(key: &str) -> Option<String> {
+    match SETTINGS.get() {
+        Some(params) => params.get(key).cloned(),
+        None => None,
+    }
+}
+
 #[test]
 fn test_make_token() {
     let fp = KPAIR.get();
@@ -62,7 +189,7 @@ fn test_make_token() {
 
     }
     let t= make_token("test".to_string(),
-    "subject".to_string(), false, Duration::from_mins(5));
+    "subject".to_string(), "".to_string(), "".to_string(), false, Duration::from_mins(5));
     if t.is_none() {
         assert!(false);
     }
@@ -73,9 +200,22 @@ fn test_make_token() {
 
 pub fn make_service(url: String, token: Option<String>) -> Mutex<Service> {
     let s = Service{
+        id: new_instance_id(),
         url: url,
-        stk: token,
+        stk: token.as_deref().map(secret_digest),
         ctr: 0,
+        hnd: 0,
+        meta: HashMap::new(),
+        hst: HealthEnum::SERVING,
+        hcf: 0,
+        sver: 0,
+        ver: "".to_string(),
+        lka: None,
+        rgt: now(),
+        lkt: None,
+        tke: None,
+        cdn: false,
+        ikey: "".to_string(),
     };
     let m = Mutex::new(s);
     m
@@ -87,45 +227,96 @@ pub fn make_protobuf(protobuf_name: &String) -> Mutex<Protobuf> {
         name: protobuf_name.to_string(),
         cltk: None,
         services: Vec::new().into(),
+        schemas: Vec::new(),
+        compat: None,
+        cdn: false,
+        ring: Default::default(),
     };
     let m = Mutex::new(p);
     m
 }
 
 // This must be called when protobufs structure has been locked. Locking is not done in called routines.
-pub fn find_protobuf(protobufs: &Protobufs, protobuf_name: String) -> Option<&Mutex<Protobuf>> {
-    let p = protobufs.protomap.get(&protobuf_name);
-    if p.is_some() {
-        return p
-    }
-    None
+pub fn find_protobuf<'a>(protobufs: &'a Protobufs, namespace: &str, protobuf_name: String)
+    -> Option<&'a Mutex<Protobuf>> {
+    protobufs.namespaces.get(&namespace_name(namespace))?.get(&protobuf_name)
 }
 
 // Add a new protobuf group to the collection of all groups
-pub fn add_protobuf(protobufs: &mut Protobufs, protobuf_name: String) -> Result<(), String> {
-    let name = protobuf_name.clone();
-    let name2 = protobuf_name.clone();
-    if ( find_protobuf(protobufs, protobuf_name)) .is_some() {
+pub fn add_protobuf(protobufs: &mut Protobufs, namespace: &str, protobuf_name: String) -> Result<(), String> {
+    if find_protobuf(protobufs, namespace, protobuf_name.clone()).is_some() {
         return Err("Protobuf is already registered".to_string())
     }
-    let p = make_protobuf(&name);
-    protobufs.protomap.insert(name2, p);
+    let p = make_protobuf(&protobuf_name);
+    protobufs.namespaces.entry(namespace_name(namespace)).or_default().insert(protobuf_name, p);
     Ok(())
 }
 
+// Take a protobuf group out of its namespace. A namespace left without
+// protobuf groups goes away with it.
+pub fn remove_protobuf_group(protobufs: &mut Protobufs, namespace: &str, protobuf_name: &str)
+    -> Option<Mutex<Protobuf>> {
+    let ns = namespace_name(namespace);
+    let protomap = protobufs.namespaces.get_mut(&ns)?;
+    let p = protomap.remove(protobuf_name);
+    if protomap.is_empty() {
+        protobufs.namespaces.remove(&ns);
+    }
+    p
+}
+
+// Drop a protobuf group once its last instance is gone. Groups holding stored
+// schemas or a compatibility mode stay so their schema history outlives the
+// instances, only the admin purge removes those. Returns true when dropped.
+pub fn release_protobuf_group(protobufs: &mut Protobufs, namespace: &str, protobuf_name: &str) -> bool {
+    let unused = find_protobuf(protobufs, namespace, protobuf_name.to_string()).is_some_and(|m| {
+        let p = m.lock().unwrap();
+        p.services.is_empty() && p.schemas.is_empty() && p.compat.is_none()
+    });
+    if unused {
+        remove_protobuf_group(protobufs, namespace, protobuf_name);
+    }
+    unused
+}
+
+// Number of instances registered in a namespace.
+pub fn instance_count(protobufs: &Protobufs, namespace: &str) -> usize {
+    match protobufs.namespaces.get(&namespace_name(namespace)) {
+        Some(protomap) => protomap.values().map(|m| m.lock().unwrap().services.len()).sum(),
+        None => 0,
+    }
+}
+
 #[test]
 fn test_add_protobuf() {
     let mut protobufs = Protobufs{
-        protomap: HashMap::new(),
+        namespaces: HashMap::new(),
     };
 
-    let p = add_protobuf(&mut protobufs, "testproto".to_string());
+    let p = add_protobuf(&mut protobufs, "", "testproto".to_string());
     if p.is_ok() {
-        let b = protobufs.protomap.contains_key(&"testproto".to_string());
-        assert_eq!(b, true,"missing protobuf")
+        assert!(find_protobuf(&protobufs, "default", "testproto".to_string()).is_some(), "missing protobuf");
     } else {
         panic!("error returned");
     }
+    // the same name in another namespace is another protobuf group
+    add_protobuf(&mut protobufs, "dev", "testproto".to_string()).unwrap();
+    assert!(add_protobuf(&mut protobufs, "dev", "testproto".to_string()).is_err());
+    add_service(&mut find_protobuf(&protobufs, "dev", "testproto".to_string()).unwrap().lock().unwrap(),
+                "url1".to_string(), None).unwrap();
+    assert_eq!(instance_count(&protobufs, "dev"), 1);
+    assert_eq!(instance_count(&protobufs, ""), 0);
+    assert!(remove_protobuf_group(&mut protobufs, "dev", "testproto").is_some());
+    assert!(!protobufs.namespaces.contains_key("dev"), "empty namespace not removed");
+    assert!(find_protobuf(&protobufs, "", "testproto".to_string()).is_some());
+
+    // a group without instances is only kept for its schemas
+    add_protobuf(&mut protobufs, "dev", "withschema".to_string()).unwrap();
+    find_protobuf(&protobufs, "dev", "withschema".to_string()).unwrap().lock().unwrap()
+        .schemas.push(vec![1]);
+    assert!(!release_protobuf_group(&mut protobufs, "dev", "withschema"), "schemas dropped");
+    assert!(release_protobuf_group(&mut protobufs, "", "testproto"));
+    assert!(find_protobuf(&protobufs, "", "testproto".to_string()).is_none());
 }
 
 // Add a new service definition to a protobuf grouping.
@@ -148,6 +339,10 @@ fn test_add_service_to_protobuf() {
         name: "proto1".to_string(),
         cltk: None,
         services: Vec::new(),
+        schemas: Vec::new(),
+        compat: None,
+        cdn: false,
+        ring: Default::default(),
     };
     let r = add_service(&mut protobuf, "url1".to_string(), None);
     if r.is_ok() {
@@ -159,6 +354,156 @@ fn test_add_service_to_protobuf() {
     }
 }
 
+// Server assigned id of a new instance.
+pub fn new_instance_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+// A write replayed from the cluster log runs with the clock and the random
+// seed of the leader that took it, so every node makes the same change.
+struct Replay {
+    time: SystemTime,
+    rng: StdRng,
+    replica: bool,      // taken by another node
+}
+
+thread_local! {
+    static REPLAY: RefCell<Option<Replay>> = const { RefCell::new(None) };
+}
+
+// Run a write replayed from the cluster log. time is in unix milliseconds.
+pub fn replay<T>(time: u64, seed: u64, replica: bool, f: impl FnOnce() -> T) -> T {
+    let time = UNIX_EPOCH + std::time::Duration::from_millis(time);
+    REPLAY.with(|r| *r.borrow_mut() = Some(Replay { time, rng: StdRng::seed_from_u64(seed), replica }));
+    let result = f();
+    REPLAY.with(|r| *r.borrow_mut() = None);
+    result
+}
+
+// Current time, the time of the write when replaying one.
+pub fn now() -> SystemTime {
+    REPLAY.with(|r| r.borrow().as_ref().map(|r| r.time)).unwrap_or_else(SystemTime::now)
+}
+
+// Random number, drawn from the seed of the write when replaying one.
+pub fn random_u64() -> u64 {
+    REPLAY.with(|r| r.borrow_mut().as_mut().map(|r| r.rng.next_u64())).unwrap_or_else(rand::random)
+}
+
+// True while replaying a write from the cluster log.
+pub fn replaying() -> bool {
+    REPLAY.with(|r| r.borrow().is_some())
+}
+
+// True while replaying a write another node took. Such writes were audited there.
+pub fn replica() -> bool {
+    REPLAY.with(|r| r.borrow().as_ref().is_some_and(|r| r.replica))
+}
+
+#[test]
+fn test_replay_is_repeatable() {
+    let run = || replay(1_700_000_000_000, 42, true, || (now(), new_instance_id(), new_instance_id(), replica()));
+    let (time, id1, id2, replica) = run();
+    assert_eq!(run(), (time, id1.clone(), id2.clone(), replica));
+    assert_eq!(time, UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000));
+    assert_ne!(id1, id2);
+    assert!(replica);
+    assert!(!replaying(), "replay context left behind");
+}
+
+// Position of an instance in the protobuf group, by its id or, when no id is
+// given, by its url. A url shared by several instances does not identify one.
+pub fn find_instance(protobuf: &Protobuf, id: &str, url: &str) -> Result<usize, String> {
+    if !id.is_empty() {
+        return match protobuf.services.iter().position(|s| s.lock().unwrap().id == id) {
+            Some(idx) => Ok(idx),
+            None => Err("no instance with this id".to_string()),
+        };
+    }
+    let found: Vec<usize> = protobuf.services.iter().enumerate()
+        .filter(|(_, s)| s.lock().unwrap().url == url)
+        .map(|(idx, _)| idx)
+        .collect();
+    match found.as_slice() {
+        [] => Err("no instance with this url".to_string()),
+        [idx] => Ok(*idx),
+        _ => Err("url is shared by several instances, give the instance id".to_string()),
+    }
+}
+
+#[test]
+fn test_find_instance() {
+    let mut protobuf = Protobuf {
+        name: "proto1".to_string(),
+        cltk: None,
+        services: Vec::new(),
+        schemas: Vec::new(),
+        compat: None,
+        cdn: false,
+        ring: Default::default(),
+    };
+    add_service(&mut protobuf, "url1".to_string(), None).unwrap();
+    add_service(&mut protobuf, "url2".to_string(), None).unwrap();
+    protobuf.services.push(make_service("url2".to_string(), None));
+    let id = protobuf.services[2].lock().unwrap().id.clone();
+    assert_eq!(id.len(), 16);
+    assert_ne!(id, protobuf.services[1].lock().unwrap().id, "instance ids are not unique");
+    assert_eq!(find_instance(&protobuf, "", "url1"), Ok(0));
+    assert_eq!(find_instance(&protobuf, &id, "url1"), Ok(2), "id does not take precedence");
+    assert!(find_instance(&protobuf, "", "url2").is_err(), "shared url identified an instance");
+    assert!(find_instance(&protobuf, "0000000000000000", "").is_err());
+}
+
+// Start a service over for a re-registration of its url. The new token
+// replaces the old one and the counters and health start from scratch as
+// for a new registration. An admin cordon is kept.
+pub fn reset_service(svc: &mut Service, token: Option<String>) {
+    svc.stk = token.as_deref().map(secret_digest);
+    svc.ctr = 0;
+    svc.hnd = 0;
+    svc.hst = HealthEnum::SERVING;
+    svc.hcf = 0;
+    svc.lka = None;
+    svc.rgt = now();
+    svc.lkt = None;
+    svc.tke = None;
+}
+
+// True when the service holds a newer registration token than the one
+// presented, so requests with the old token are refused.
+pub fn token_replaced(svc: &Service, token: &str) -> bool {
+    svc.stk.as_deref().is_some_and(|t| t != secret_digest(token))
+}
+
+// The registry keeps a digest of the tokens it issued and of instance keys,
+// enough to recognise them, so state files and replication never carry the
+// secrets. An empty key stays empty.
+pub fn secret_digest(secret: &str) -> String {
+    if secret.is_empty() {
+        return String::new();
+    }
+    hmac_sha256::Hash::hash(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn test_reset_service() {
+    let m = make_service("url1".to_string(), Some("old".to_string()));
+    let mut svc = m.lock().unwrap();
+    svc.ctr = 5;
+    svc.hcf = 2;
+    svc.hst = HealthEnum::NOTSERVING;
+    svc.cdn = true;
+    svc.lkt = Some(SystemTime::now());
+    reset_service(&mut svc, Some("new".to_string()));
+    assert_eq!(svc.ctr, 0);
+    assert_eq!(svc.hcf, 0);
+    assert_eq!(svc.hst, HealthEnum::SERVING);
+    assert!(svc.lkt.is_none());
+    assert!(svc.cdn, "cordon lifted by re-registration");
+    assert!(token_replaced(&svc, "old"));
+    assert!(!token_replaced(&svc, "new"));
+}
+
 // Check for duplicate protobuf urls in this protobuf group.
 fn check_for_dup_urls(protobuf: &Protobuf, url: String) -> bool {
     let ct = protobuf.services.len();
@@ -177,12 +522,29 @@ fn duplicate_in_service_list() {
         name: "proto1".to_string(),
         cltk: None,
         services: Vec::new(),
+        schemas: Vec::new(),
+        compat: None,
+        cdn: false,
+        ring: Default::default(),
     };
     for i in 0..5 {
         let s = Service {
+            id: new_instance_id(),
             url: format ! ("url-{}", i),
             stk: None,
             ctr: 0,
+            hnd: 0,
+            meta: HashMap::new(),
+            hst: HealthEnum::SERVING,
+            hcf: 0,
+            sver: 0,
+            ver: "".to_string(),
+            lka: None,
+            rgt: SystemTime::now(),
+            lkt: None,
+            tke: None,
+            cdn: false,
+            ikey: "".to_string(),
         };
         let m = Mutex::new(s);
         protobuf.services.push(m);
@@ -201,6 +563,27 @@ pub enum StatusEnum {
     BADTOKEN  = 3,  // Invalid auth token
     AUTHERROR = 4,  // token create error
     SERVERROR = 5,  // server error
+    INCOMPATIBLE = 6,  // schema breaks registered instances
+    EXHAUSTED = 7,  // quota or cap reached, sent as gRPC RESOURCE_EXHAUSTED
+}
+
+// Enum to match protobuf enum for instance health
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthEnum {
+    SERVING    = 0,  // available to FIND
+    DRAINING   = 1,  // finishing in-flight work, kept in reports only
+    NOTSERVING = 2,  // registered but unavailable
+}
+
+impl HealthEnum {
+    pub fn from_i32(value: i32) -> Option<HealthEnum> {
+        match value {
+            0 => Some(HealthEnum::SERVING),
+            1 => Some(HealthEnum::DRAINING),
+            2 => Some(HealthEnum::NOTSERVING),
+            _ => None,
+        }
+    }
 }
 
 // Common routine to make a status packet
@@ -0,0 +1,400 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Schema compatibility checks. A provider registering a descriptor set is
+// compared with the schemas of the instances already registered under the
+// same protobuf name. Changed field numbers and changed field or RPC types are
+// breaking changes, and so are removed messages, fields and RPCs in the modes
+// that check backward. Additions never break.
+
+use std::collections::HashMap;
+use prost_types::{DescriptorProto, FieldDescriptorProto, FileDescriptorSet};
+use crate::common::make_status_packet;
+use crate::registry::{CompatRequest, CompatResponse};
+use crate::{common, schemas, Protobuf, GDATA};
+
+// Enum to match protobuf enum for compatibility modes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompatEnum {
+    DEFAULT  = 0,  // use the registry default
+    BACKWARD = 1,  // the new schema must not break the registered ones
+    FORWARD  = 2,  // the registered schemas must not break the new one
+    FULL     = 3,  // both backward and forward
+    NONE     = 4,  // no checks
+}
+
+impl CompatEnum {
+    pub fn from_i32(value: i32) -> Option<CompatEnum> {
+        match value {
+            0 => Some(CompatEnum::DEFAULT),
+            1 => Some(CompatEnum::BACKWARD),
+            2 => Some(CompatEnum::FORWARD),
+            3 => Some(CompatEnum::FULL),
+            4 => Some(CompatEnum::NONE),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<CompatEnum> {
+        match name.to_lowercase().as_str() {
+            "backward" => Some(CompatEnum::BACKWARD),
+            "forward" => Some(CompatEnum::FORWARD),
+            "full" => Some(CompatEnum::FULL),
+            "none" => Some(CompatEnum::NONE),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompatEnum::DEFAULT => "default",
+            CompatEnum::BACKWARD => "backward",
+            CompatEnum::FORWARD => "forward",
+            CompatEnum::FULL => "full",
+            CompatEnum::NONE => "none",
+        }
+    }
+}
+
+// Registry wide mode used for names without their own setting. Configured by
+// schema_compatibility in setting.toml, backward when not set.
+pub fn default_mode() -> CompatEnum {
+    match common::setting("schema_compatibility") {
+        Some(v) => CompatEnum::from_name(&v).unwrap_or(CompatEnum::BACKWARD),
+        None => CompatEnum::BACKWARD,
+    }
+}
+
+// Mode in effect for a protobuf name.
+pub fn effective_mode(mode: Option<CompatEnum>) -> CompatEnum {
+    match mode {
+        Some(m) if m != CompatEnum::DEFAULT => m,
+        _ => default_mode(),
+    }
+}
+
+// Full names of all messages, including nested ones, mapped to their descriptor.
+fn index_messages(fds: &FileDescriptorSet) -> HashMap<String, &DescriptorProto> {
+    fn walk<'a>(prefix: &str, msgs: &'a [DescriptorProto],
+                index: &mut HashMap<String, &'a DescriptorProto>) {
+        for m in msgs {
+            let name = format!("{}.{}", prefix, m.name());
+            walk(&name, &m.nested_type, index);
+            index.insert(name, m);
+        }
+    }
+    let mut index = HashMap::new();
+    for file in fds.file.iter() {
+        walk(file.package(), &file.message_type, &mut index);
+    }
+    index
+}
+
+// Full names of all rpcs mapped to their input and output types.
+fn index_methods(fds: &FileDescriptorSet) -> HashMap<String, (String, String)> {
+    let mut index = HashMap::new();
+    for file in fds.file.iter() {
+        for svc in file.service.iter() {
+            for m in svc.method.iter() {
+                let name = format!("{}.{}/{}", file.package(), svc.name(), m.name());
+                index.insert(name, (m.input_type().to_string(), m.output_type().to_string()));
+            }
+        }
+    }
+    index
+}
+
+fn field_type(f: &FieldDescriptorProto) -> String {
+    format!("{:?} {:?} {}", f.label(), f.r#type(), f.type_name())
+}
+
+// Changes in the new schema that break users of the old one.
+pub fn breaking_changes(old: &FileDescriptorSet, new: &FileDescriptorSet) -> Vec<String> {
+    changes(old, new, true)
+}
+
+// Changes to the fields and RPCs both schemas have, and with removals also
+// what the old schema has and the new one lacks.
+fn changes(old: &FileDescriptorSet, new: &FileDescriptorSet, removals: bool) -> Vec<String> {
+    let mut issues = Vec::new();
+    let new_messages = index_messages(new);
+    for (name, old_msg) in index_messages(old) {
+        let new_msg = match new_messages.get(&name) {
+            Some(m) => m,
+            None => {
+                if removals {
+                    issues.push(format!("message {} removed", name));
+                }
+                continue;
+            },
+        };
+        for old_field in old_msg.field.iter() {
+            let by_number = new_msg.field.iter().find(|f| f.number() == old_field.number());
+            let by_name = new_msg.field.iter().find(|f| f.name() == old_field.name());
+            match (by_number, by_name) {
+                (Some(f), _) => {
+                    if field_type(f) != field_type(old_field) {
+                        issues.push(format!("field {}.{} type changed", name, old_field.name()));
+                    }
+                },
+                (None, Some(f)) => {
+                    issues.push(format!("field {}.{} number changed from {} to {}",
+                                        name, old_field.name(), old_field.number(), f.number()));
+                },
+                (None, None) => {
+                    if removals {
+                        issues.push(format!("field {}.{} removed", name, old_field.name()));
+                    }
+                },
+            }
+        }
+    }
+    let new_methods = index_methods(new);
+    for (name, old_types) in index_methods(old) {
+        match new_methods.get(&name) {
+            Some(new_types) => {
+                if *new_types != old_types {
+                    issues.push(format!("rpc {} types changed", name));
+                }
+            },
+            None => {
+                if removals {
+                    issues.push(format!("rpc {} removed", name));
+                }
+            },
+        }
+    }
+    issues.sort();
+    issues
+}
+
+// Check a new schema against a registered one under the given mode. Readers
+// of the registered schema skip what the new one adds, so forward only rejects
+// changes to what both have. Backward, and full with it, also rejects removals.
+pub fn check(mode: CompatEnum, registered: &FileDescriptorSet, new: &FileDescriptorSet) -> Vec<String> {
+    match mode {
+        CompatEnum::NONE => Vec::new(),
+        CompatEnum::FORWARD => changes(registered, new, false),
+        _ => breaking_changes(registered, new),
+    }
+}
+
+// Check a schema sent with a registration against the schema versions of the
+// instances already registered under the protobuf, or the latest stored
+// version when none are left. Returns the breaking changes found, prefixed
+// with the version they break. The protobuf must already be locked.
+pub fn check_registered(protobuf: &Protobuf, data: &[u8]) -> Vec<String> {
+    let mode = effective_mode(protobuf.compat);
+    if mode == CompatEnum::NONE {
+        return Vec::new();
+    }
+    let new = match schemas::decode_schema(data) {
+        Ok(v) => v,
+        Err(e) => return vec![e],
+    };
+    let mut versions: Vec<i32> = protobuf.services.iter()
+        .map(|s| s.lock().unwrap().sver)
+        .filter(|v| *v > 0)
+        .collect();
+    versions.sort();
+    versions.dedup();
+    if versions.is_empty() && !protobuf.schemas.is_empty() {
+        versions.push(protobuf.schemas.len() as i32);
+    }
+    let mut issues = Vec::new();
+    for version in versions {
+        let stored = &protobuf.schemas[(version - 1) as usize];
+        if stored.as_slice() == data {
+            continue;
+        }
+        match schemas::decode_schema(stored) {
+            Ok(old) => {
+                for issue in check(mode, &old, &new) {
+                    issues.push(format!("version {}: {}", version, issue));
+                }
+            },
+            Err(e) => issues.push(format!("version {}: {}", version, e)),
+        }
+    }
+    issues
+}
+
+// Checks run by a mode, backward and forward.
+fn checks(mode: CompatEnum) -> (bool, bool) {
+    match mode {
+        CompatEnum::BACKWARD => (true, false),
+        CompatEnum::FORWARD => (false, true),
+        CompatEnum::FULL => (true, true),
+        _ => (false, false),
+    }
+}
+
+// A change weakens the mode when it drops a check the current mode runs.
+pub fn weakens(current: CompatEnum, new: CompatEnum) -> bool {
+    let (cb, cf) = checks(current);
+    let (nb, nf) = checks(new);
+    (cb && !nb) || (cf && !nf)
+}
+
+// Set the compatibility mode of a protobuf name. A provider registered under
+// that name may make the mode stricter. Weakening it, or setting it on a name
+// nobody registered yet, needs an admin token.
+pub fn handle_compat(req: CompatRequest) -> CompatResponse {
+    let claim = match common::check_scoped_token("compat", req.token, &req.namespace) {
+        Ok(c) => c,
+        Err(e) => {
+            let s = make_status_packet(common::StatusEnum::AUTHERROR, e);
+            return compat_response(0, Some(s));
+        },
+    };
+    let admin = claim.custom.user_is_admin
+        && common::setting("admin_secret").is_some_and(|s| !s.is_empty());
+    if !admin && (claim.subject.as_deref() != Some(req.protobuf_name.as_str())
+        || claim.custom.user_name == "client") {
+        let s = make_status_packet(common::StatusEnum::BADTOKEN,
+                                   "token is not a provider token for this protobuf".to_string());
+        return compat_response(0, Some(s));
+    }
+    let mode = match CompatEnum::from_i32(req.mode) {
+        Some(m) => m,
+        None => {
+            let s = make_status_packet(common::StatusEnum::SERVERROR,
+                                       "unknown compatibility mode".to_string());
+            return compat_response(0, Some(s));
+        },
+    };
+    let mode = if mode == CompatEnum::DEFAULT { None } else { Some(mode) };
+
+    let mut protobufs = GDATA.get().unwrap().lock().unwrap();
+    if common::find_protobuf(&protobufs, &req.namespace, req.protobuf_name.clone()).is_none() {
+        if !admin {
+            let s = make_status_packet(common::StatusEnum::NOTFOUND,
+                                       "protobuf does not exist".to_string());
+            return compat_response(0, Some(s));
+        }
+        if let Err(e) = common::check_namespace(&req.namespace) {
+            let s = make_status_packet(common::StatusEnum::SERVERROR, e);
+            return compat_response(0, Some(s));
+        }
+        let _ = common::add_protobuf(&mut protobufs, &req.namespace, req.protobuf_name.clone());
+    }
+    let mut protobuf = common::find_protobuf(&protobufs, &req.namespace, req.protobuf_name)
+        .unwrap().lock().unwrap();
+    let (current, new) = (effective_mode(protobuf.compat), effective_mode(mode));
+    if !admin && weakens(current, new) {
+        let s = make_status_packet(common::StatusEnum::AUTHERROR,
+            format!("changing the mode from {} to {} needs an admin token", current.name(), new.name()));
+        return compat_response(current as i32, Some(s));
+    }
+    protobuf.compat = mode;
+    compat_response(new as i32, None)
+}
+
+fn compat_response(mode: i32, status: Option<crate::registry::StatusPacket>) -> CompatResponse {
+    CompatResponse {
+        mode,
+        status,
+    }
+}
+
+#[cfg(test)]
+fn test_schema(fields: &[(&str, i32, i32)], rpcs: &[&str]) -> FileDescriptorSet {
+    use prost_types::{FileDescriptorProto, MethodDescriptorProto, ServiceDescriptorProto};
+    let msg = DescriptorProto {
+        name: Some("Item".to_string()),
+        field: fields.iter().map(|(name, number, ty)| FieldDescriptorProto {
+            name: Some(name.to_string()),
+            number: Some(*number),
+            r#type: Some(*ty),
+            ..Default::default()
+        }).collect(),
+        ..Default::default()
+    };
+    let svc = ServiceDescriptorProto {
+        name: Some("Store".to_string()),
+        method: rpcs.iter().map(|name| MethodDescriptorProto {
+            name: Some(name.to_string()),
+            input_type: Some(".test.Item".to_string()),
+            output_type: Some(".test.Item".to_string()),
+            ..Default::default()
+        }).collect(),
+        ..Default::default()
+    };
+    FileDescriptorSet {
+        file: vec![FileDescriptorProto {
+            name: Some("test.proto".to_string()),
+            package: Some("test".to_string()),
+            message_type: vec![msg],
+            service: vec![svc],
+            ..Default::default()
+        }],
+    }
+}
+
+#[test]
+fn test_breaking_changes() {
+    // 9 is string, 5 is int32 in FieldDescriptorProto.Type
+    let base = test_schema(&[("id", 1, 9), ("count", 2, 5)], &["Get", "Put"]);
+    let added = test_schema(&[("id", 1, 9), ("count", 2, 5), ("note", 3, 9)], &["Get", "Put", "List"]);
+    assert!(breaking_changes(&base, &added).is_empty(), "additions are compatible");
+    for mode in [CompatEnum::BACKWARD, CompatEnum::FORWARD, CompatEnum::FULL, CompatEnum::NONE] {
+        assert!(check(mode, &base, &added).is_empty(), "additions rejected under {}", mode.name());
+    }
+    assert!(check(CompatEnum::NONE, &base, &test_schema(&[], &[])).is_empty());
+
+    // removals break backward and full, not forward
+    let removed = test_schema(&[("id", 1, 9)], &["Get"]);
+    let issues = breaking_changes(&base, &removed);
+    assert_eq!(issues, vec!["field test.Item.count removed".to_string(),
+                            "rpc test.Store/Put removed".to_string()]);
+    assert_eq!(check(CompatEnum::FULL, &base, &removed), issues);
+    assert!(check(CompatEnum::FORWARD, &base, &removed).is_empty());
+
+    // changes to shared fields break every checking mode
+    let renumbered = test_schema(&[("id", 1, 9), ("count", 4, 5)], &["Get", "Put"]);
+    let retyped = test_schema(&[("id", 1, 5), ("count", 2, 5)], &["Get", "Put"]);
+    for mode in [CompatEnum::BACKWARD, CompatEnum::FORWARD, CompatEnum::FULL] {
+        assert_eq!(check(mode, &base, &renumbered),
+                   vec!["field test.Item.count number changed from 2 to 4".to_string()]);
+        assert_eq!(check(mode, &base, &retyped), vec!["field test.Item.id type changed".to_string()]);
+    }
+}
+
+#[test]
+fn test_weakens() {
+    assert!(weakens(CompatEnum::BACKWARD, CompatEnum::NONE));
+    assert!(weakens(CompatEnum::FULL, CompatEnum::FORWARD));
+    assert!(weakens(CompatEnum::BACKWARD, CompatEnum::FORWARD));
+    assert!(!weakens(CompatEnum::BACKWARD, CompatEnum::FULL));
+    assert!(!weakens(CompatEnum::NONE, CompatEnum::FORWARD));
+    assert!(!weakens(CompatEnum::FULL, CompatEnum::FULL));
+}
+
+#[test]
+fn test_check_without_instances() {
+    use prost::Message;
+    let base = test_schema(&[("id", 1, 9), ("count", 2, 5)], &["Get", "Put"]);
+    let removed = test_schema(&[("id", 1, 9)], &["Get"]);
+    let m = common::make_protobuf(&"proto1".to_string());
+    let mut protobuf = m.lock().unwrap();
+    assert!(check_registered(&protobuf, &removed.encode_to_vec()).is_empty());
+    // the stored schema still counts after every instance left
+    protobuf.compat = Some(CompatEnum::BACKWARD);
+    protobuf.schemas.push(base.encode_to_vec());
+    let issues = check_registered(&protobuf, &removed.encode_to_vec());
+    assert!(issues.iter().any(|i| i.starts_with("version 1: field test.Item.count removed")), "{:?}", issues);
+}
@@ -0,0 +1,269 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Provider report rendering. A ProviderReportResponse is turned into JSON,
+// YAML, CSV or an aligned text table. Shared by the server's HTTP admin
+// endpoint and reg-client so both produce the same output.
+
+use std::collections::BTreeMap;
+use serde::Serialize;
+use crate::registry::{ByProviderInstance, HealthState, ProviderReportResponse};
+
+// Enum for the supported output formats
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    JSON  = 0,
+    YAML  = 1,
+    CSV   = 2,
+    TABLE = 3,
+}
+
+impl ReportFormat {
+    pub fn from_name(name: &str) -> Option<ReportFormat> {
+        match name.to_lowercase().as_str() {
+            "json" => Some(ReportFormat::JSON),
+            "yaml" | "yml" => Some(ReportFormat::YAML),
+            "csv" => Some(ReportFormat::CSV),
+            "table" | "text" => Some(ReportFormat::TABLE),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ReportFormat::JSON => "application/json",
+            ReportFormat::YAML => "application/yaml",
+            ReportFormat::CSV => "text/csv",
+            ReportFormat::TABLE => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ReportView {
+    #[serde(skip_serializing_if = "String::is_empty")]
+    namespace: String,
+    instances: i32,         // instances registered in the namespace
+    instance_quota: i32,    // 0 for no limit
+    providers: Vec<ProviderView>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    next_page_token: String,
+}
+
+#[derive(Serialize)]
+struct ProviderView {
+    protobuf_name: String,
+    instances: Vec<InstanceView>,
+}
+
+#[derive(Serialize)]
+struct InstanceView {
+    instance_id: String,
+    service_url: String,
+    version: String,
+    health: String,
+    requests: i32,
+    handed_out: i32,
+    registered: String,
+    last_report: String,
+    token_expires: String,
+    cordoned: bool,
+    metadata: BTreeMap<String, String>,
+}
+
+const COLUMNS: [&str; 12] = ["PROTOBUF", "URL", "VERSION", "HEALTH", "CORDONED", "REQUESTS",
+                             "HANDED_OUT", "REGISTERED", "LAST_REPORT", "TOKEN_EXPIRES",
+                             "INSTANCE_ID", "METADATA"];
+
+fn instance_view(inst: &ByProviderInstance) -> InstanceView {
+    let time = |t: &Option<prost_types::Timestamp>| t.as_ref().map(|t| t.to_string()).unwrap_or_default();
+    InstanceView {
+        instance_id: inst.instance_id.clone(),
+        service_url: inst.service_url.clone(),
+        version: inst.version.clone(),
+        health: HealthState::try_from(inst.health)
+            .map(|h| h.as_str_name().to_string())
+            .unwrap_or(inst.health.to_string()),
+        requests: inst.requests,
+        handed_out: inst.handed_out,
+        registered: time(&inst.registered),
+        last_report: time(&inst.last_report),
+        token_expires: time(&inst.token_expires),
+        cordoned: inst.cordoned,
+        metadata: inst.metadata.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+    }
+}
+
+fn report_view(rsp: &ProviderReportResponse) -> ReportView {
+    ReportView {
+        namespace: rsp.namespace.clone(),
+        instances: rsp.instances,
+        instance_quota: rsp.instance_quota,
+        providers: rsp.providers.iter().map(|p| ProviderView {
+            protobuf_name: p.protobuf_name.clone(),
+            instances: p.instances.iter().map(instance_view).collect(),
+        }).collect(),
+        next_page_token: rsp.next_page_token.clone(),
+    }
+}
+
+// One row per instance for the flat formats.
+fn rows(view: &ReportView) -> Vec<[String; 12]> {
+    let mut rows = Vec::new();
+    for p in view.providers.iter() {
+        for i in p.instances.iter() {
+            let labels: Vec<String> = i.metadata.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+            rows.push([p.protobuf_name.clone(), i.service_url.clone(), i.version.clone(),
+                       i.health.clone(), i.cordoned.to_string(), i.requests.to_string(),
+                       i.handed_out.to_string(),
+                       i.registered.clone(), i.last_report.clone(), i.token_expires.clone(),
+                       i.instance_id.clone(), labels.join(";")]);
+        }
+    }
+    rows
+}
+
+fn to_csv(view: &ReportView) -> Result<String, String> {
+    let mut w = csv::Writer::from_writer(Vec::new());
+    let mut result = w.write_record(COLUMNS.iter().map(|c| c.to_lowercase()));
+    for row in rows(view) {
+        if result.is_ok() {
+            result = w.write_record(&row);
+        }
+    }
+    if let Err(e) = result {
+        return Err(format!("failed to write csv: {}", e));
+    }
+    match w.into_inner() {
+        Ok(data) => Ok(String::from_utf8_lossy(&data).into_owned()),
+        Err(e) => Err(format!("failed to write csv: {}", e)),
+    }
+}
+
+fn to_table(view: &ReportView) -> String {
+    let rows = rows(view);
+    let mut widths: Vec<usize> = COLUMNS.iter().map(|c| c.len()).collect();
+    for row in rows.iter() {
+        for (n, cell) in row.iter().enumerate() {
+            widths[n] = widths[n].max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().enumerate()
+            .map(|(n, c)| format!("{:width$}", c, width = widths[n]))
+            .collect();
+        format!("{}\n", padded.join("  ").trim_end())
+    };
+    let mut out = line(COLUMNS.to_vec());
+    for row in rows.iter() {
+        out.push_str(&line(row.iter().map(|c| c.as_str()).collect()));
+    }
+    if !view.next_page_token.is_empty() {
+        out.push_str(&format!("next page: {}\n", view.next_page_token));
+    }
+    if !view.namespace.is_empty() {
+        let quota = match view.instance_quota {
+            0 => "no quota".to_string(),
+            q => format!("quota {}", q),
+        };
+        out.push_str(&format!("namespace {}: {} instances, {}\n", view.namespace, view.instances, quota));
+    }
+    out
+}
+
+// Parse a key=value label filter.
+pub fn parse_label(value: &str) -> Result<(String, String), String> {
+    match value.split_once('=') {
+        Some((k, v)) => Ok((k.to_string(), v.to_string())),
+        None => Err(format!("label '{}' is not key=value", value)),
+    }
+}
+
+// Parse a health state name such as serving or NOT_SERVING.
+pub fn parse_health(value: &str) -> Result<i32, String> {
+    match HealthState::from_str_name(&value.to_uppercase()) {
+        Some(h) => Ok(h as i32),
+        None => Err(format!("unknown health '{}'", value)),
+    }
+}
+
+// Render the report in the requested format.
+pub fn render_report(rsp: &ProviderReportResponse, format: ReportFormat) -> Result<String, String> {
+    let view = report_view(rsp);
+    match format {
+        ReportFormat::JSON => serde_json::to_string_pretty(&view)
+            .map(|s| s + "\n")
+            .map_err(|e| format!("failed to write json: {}", e)),
+        ReportFormat::YAML => serde_yaml::to_string(&view)
+            .map_err(|e| format!("failed to write yaml: {}", e)),
+        ReportFormat::CSV => to_csv(&view),
+        ReportFormat::TABLE => Ok(to_table(&view)),
+    }
+}
+
+#[test]
+fn test_render_report_formats() {
+    use std::collections::HashMap;
+    use crate::registry::ByProvider;
+
+    let inst = ByProviderInstance {
+        service_url: "localhost:8089".to_string(),
+        requests: 12,
+        health: HealthState::Draining as i32,
+        version: "1.2.0".to_string(),
+        cordoned: true,
+        registered: Some(prost_types::Timestamp { seconds: 1_700_000_000, nanos: 0 }),
+        metadata: HashMap::from([("zone".to_string(), "east, 1".to_string())]),
+        ..Default::default()
+    };
+    let rsp = ProviderReportResponse {
+        providers: vec![ByProvider {
+            protobuf_name: "proto1".to_string(),
+            instances: vec![inst],
+            versions: Vec::new(),
+            cordoned: false,
+        }],
+        status: None,
+        next_page_token: "".to_string(),
+        namespace: "dev".to_string(),
+        instances: 1,
+        instance_quota: 20,
+    };
+
+    let json = render_report(&rsp, ReportFormat::JSON).unwrap();
+    let v: serde_json::Value = serde_json::from_str(&json).unwrap();
+    assert_eq!(v["providers"][0]["instances"][0]["health"], "DRAINING");
+    assert_eq!(v["providers"][0]["instances"][0]["cordoned"], true);
+    assert_eq!(v["providers"][0]["instances"][0]["registered"], "2023-11-14T22:13:20Z");
+    assert_eq!(v["providers"][0]["instances"][0]["metadata"]["zone"], "east, 1");
+    assert_eq!(v["namespace"], "dev");
+    assert_eq!(v["instance_quota"], 20);
+
+    let yaml = render_report(&rsp, ReportFormat::YAML).unwrap();
+    assert!(yaml.contains("protobuf_name: proto1"), "{}", yaml);
+
+    let csv = render_report(&rsp, ReportFormat::CSV).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert!(lines[0].starts_with("protobuf,url,version,health"));
+    assert!(lines[1].contains("\"zone=east, 1\""), "{}", csv);
+
+    let table = render_report(&rsp, ReportFormat::TABLE).unwrap();
+    let lines: Vec<&str> = table.lines().collect();
+    assert!(lines[0].starts_with("PROTOBUF  URL"));
+    assert_eq!(lines[0].find("VERSION"), lines[1].find("1.2.0"), "columns not aligned");
+    assert_eq!(lines[2], "namespace dev: 1 instances, quota 20");
+}
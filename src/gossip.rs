@@ -0,0 +1,557 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Gossip replication, a lighter option than clustering for sites that can live
+// with brief inconsistency but not with a central node. Every node takes reads
+// and writes. Each instance and each protobuf name is a record stamped with
+// the wall clock time of its last change and the node that made it, and the
+// later stamp wins. Local changes are found by comparing the registry with the
+// records, removals leave tombstones. Every gossip_interval_ms a node sends the
+// versions of its records to a random node from gossip_peers and the two swap
+// only the records one holds a later version of. Messages are signed with
+// gossip_secret and records hold digests of tokens and instance keys only.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use prost::Message;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use tonic::transport::{Channel, Server};
+use tonic::{Request, Response, Status};
+use tracing::{debug, error, info, warn};
+use crate::compat::CompatEnum;
+use crate::registry::gossip_client::GossipClient;
+use crate::registry::gossip_server::{Gossip, GossipServer};
+use crate::registry::{GossipPush, GossipPushResponse, GossipRequest, GossipResponse, RecordVersion};
+use crate::state::{ProtobufState, ServiceState};
+use crate::{admin, common, config_number, metrics, state, Protobufs, GDATA};
+
+// Tombstones are dropped after an hour, every node has seen them by then.
+const TOMBSTONE_TTL: u64 = 3600 * 1000;
+// Messages sent longer ago than this, by the clock of the receiver, are refused.
+const MAX_SKEW: u64 = 60 * 1000;
+
+type Key = (String, String, String);
+type Current = (Option<ProtobufState>, Option<ServiceState>);
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub namespace: String,
+    pub name: String,
+    pub id: String,     // instance id, empty for the protobuf name itself
+    pub ts: u64,        // unix milliseconds of the change
+    pub node: String,   // node that made the change, breaks ties
+    pub protobuf: Option<ProtobufState>,    // name without its services
+    pub service: Option<ServiceState>,      // both None for a removal
+}
+
+impl Record {
+    fn key(&self) -> Key {
+        (self.namespace.clone(), self.name.clone(), self.id.clone())
+    }
+
+    fn version(&self) -> (u64, &str) {
+        (self.ts, &self.node)
+    }
+
+    fn live(&self) -> bool {
+        self.protobuf.is_some() || self.service.is_some()
+    }
+
+    fn holds(&self, current: &Current) -> bool {
+        self.protobuf == current.0 && self.service == current.1
+    }
+
+    fn digest(&self) -> RecordVersion {
+        RecordVersion {
+            namespace: self.namespace.clone(),
+            name: self.name.clone(),
+            id: self.id.clone(),
+            ts: self.ts,
+            node: self.node.clone(),
+        }
+    }
+}
+
+fn version_key(v: &RecordVersion) -> Key {
+    (v.namespace.clone(), v.name.clone(), v.id.clone())
+}
+
+// The records of a node. Lock order is the records, then the registry, which
+// is held only to copy it or to apply records that won.
+pub struct Replica {
+    id: String,
+    records: Mutex<HashMap<Key, Record>>,
+}
+
+impl Replica {
+    pub fn new(id: String) -> Replica {
+        Replica { id, records: Mutex::new(HashMap::new()) }
+    }
+
+    // Stamp the local changes.
+    pub fn refresh(&self, registry: &Mutex<Protobufs>, now: u64) {
+        let mut records = self.records.lock().unwrap();
+        let current = scan(&registry.lock().unwrap());
+        self.observe(&mut records, current, now);
+    }
+
+    // Versions of all records, sent to a peer in place of the records.
+    pub fn versions(&self) -> Vec<RecordVersion> {
+        self.records.lock().unwrap().values().map(Record::digest).collect()
+    }
+
+    // Compare the versions of a peer with ours. Returns the records we hold
+    // later versions of and the versions the peer holds later.
+    pub fn compare(&self, remote: &[RecordVersion]) -> (Vec<Record>, Vec<RecordVersion>) {
+        let records = self.records.lock().unwrap();
+        let remote: HashMap<Key, &RecordVersion> = remote.iter().map(|v| (version_key(v), v)).collect();
+        let newer = records.iter()
+            .filter(|(key, r)| remote.get(*key).is_none_or(|v| (v.ts, v.node.as_str()) < r.version()))
+            .map(|(_, r)| r.clone()).collect();
+        let wanted = remote.iter()
+            .filter(|(key, v)| records.get(*key).is_none_or(|r| r.version() < (v.ts, v.node.as_str())))
+            .map(|(_, v)| (*v).clone()).collect();
+        (newer, wanted)
+    }
+
+    // Our records for the versions a peer asked for.
+    pub fn records(&self, wanted: &[RecordVersion]) -> Vec<Record> {
+        let records = self.records.lock().unwrap();
+        wanted.iter().filter_map(|v| records.get(&version_key(v)).cloned()).collect()
+    }
+
+    // Take the remote records that are later than ours. A record whose
+    // registry entry changed since the last refresh waits for the next round,
+    // when the local change is stamped and compared.
+    pub fn merge(&self, registry: &Mutex<Protobufs>, mut remote: Vec<Record>) {
+        let mut records = self.records.lock().unwrap();
+        let mut protobufs = registry.lock().unwrap();
+        // names before their instances, removed names after so they are empty
+        remote.sort_by_key(|r| match (r.id.is_empty(), r.live()) {
+            (true, true) => 0,
+            (false, _) => 1,
+            (true, false) => 2,
+        });
+        for record in remote {
+            let key = record.key();
+            let ours = records.get(&key);
+            if ours.is_some_and(|r| r.version() >= record.version()) {
+                continue;
+            }
+            let current = current(&protobufs, &key);
+            if !ours.map_or(current == (None, None), |r| r.holds(&current)) {
+                continue;
+            }
+            apply(&mut protobufs, &record);
+            records.insert(key, record);
+        }
+    }
+
+    fn observe(&self, records: &mut HashMap<Key, Record>, current: Vec<(Key, Current)>, now: u64) {
+        let mut seen = HashSet::new();
+        for (key, (protobuf, service)) in current {
+            self.note(records, &mut seen, now, key, protobuf, service);
+        }
+        for (key, record) in records.iter_mut() {
+            if record.live() && !seen.contains(key) {
+                record.ts = now.max(record.ts + 1);
+                record.node = self.id.clone();
+                record.protobuf = None;
+                record.service = None;
+            }
+        }
+        records.retain(|_, r| r.live() || now.saturating_sub(r.ts) < TOMBSTONE_TTL);
+    }
+
+    fn note(&self, records: &mut HashMap<Key, Record>, seen: &mut HashSet<Key>, now: u64, key: Key,
+            protobuf: Option<ProtobufState>, service: Option<ServiceState>) {
+        seen.insert(key.clone());
+        let ts = match records.get(&key) {
+            Some(r) if r.protobuf == protobuf && r.service == service => return,
+            // a change always wins over the version it replaces
+            Some(r) => now.max(r.ts + 1),
+            None => now,
+        };
+        let (namespace, name, id) = key.clone();
+        records.insert(key, Record { namespace, name, id, ts, node: self.id.clone(), protobuf, service });
+    }
+}
+
+// Copy every protobuf name and instance of the registry in record form.
+fn scan(protobufs: &Protobufs) -> Vec<(Key, Current)> {
+    let mut current = Vec::new();
+    for (ns, protomap) in protobufs.namespaces.iter() {
+        for (name, m) in protomap.iter() {
+            let protobuf = m.lock().unwrap();
+            let pstate = state::protobuf_state(ns, &protobuf);
+            current.push(((ns.clone(), name.clone(), String::new()), (Some(pstate), None)));
+            for s in protobuf.services.iter() {
+                let sstate = state::service_state(&s.lock().unwrap());
+                current.push(((ns.clone(), name.clone(), sstate.id.clone()), (None, Some(sstate))));
+            }
+        }
+    }
+    current
+}
+
+// The record form of one protobuf name or instance, both None when missing.
+fn current(protobufs: &Protobufs, (ns, name, id): &Key) -> Current {
+    let Some(m) = common::find_protobuf(protobufs, ns, name.clone()) else {
+        return (None, None);
+    };
+    let protobuf = m.lock().unwrap();
+    if id.is_empty() {
+        return (Some(state::protobuf_state(ns, &protobuf)), None);
+    }
+    let service = protobuf.services.iter().map(|s| s.lock().unwrap())
+        .find(|s| &s.id == id).map(|s| state::service_state(&s));
+    (None, service)
+}
+
+// Bring the registry in line with a record that won. Counters that are not
+// replicated, such as FIND hand-outs, are kept for existing instances.
+fn apply(protobufs: &mut Protobufs, record: &Record) {
+    let (ns, name) = (&record.namespace, &record.name);
+    if record.live() && common::find_protobuf(protobufs, ns, name.clone()).is_none() {
+        let _ = common::add_protobuf(protobufs, ns, name.clone());
+    }
+    if let Some(pstate) = &record.protobuf {
+        let mut protobuf = common::find_protobuf(protobufs, ns, name.clone()).unwrap().lock().unwrap();
+        protobuf.cltk = pstate.cltk.clone();
+        protobuf.schemas = pstate.schemas.clone();
+        protobuf.compat = pstate.compat.as_ref().and_then(|m| CompatEnum::from_name(m));
+        protobuf.cdn = pstate.cdn;
+    } else if let Some(sstate) = &record.service {
+        let mut protobuf = common::find_protobuf(protobufs, ns, name.clone()).unwrap().lock().unwrap();
+        let service = state::restore_service(sstate.clone());
+        match protobuf.services.iter().find(|s| s.lock().unwrap().id == record.id) {
+            Some(s) => {
+                let mut svc = s.lock().unwrap();
+                let (hnd, hcf, lka) = (svc.hnd, svc.hcf, svc.lka);
+                *svc = service;
+                (svc.hnd, svc.hcf, svc.lka) = (hnd, hcf, lka);
+            },
+            None => protobuf.services.push(Mutex::new(service)),
+        }
+    } else if record.id.is_empty() {
+        // the name stays while it has instances newer than its removal
+        let empty = common::find_protobuf(protobufs, ns, name.clone())
+            .is_some_and(|p| p.lock().unwrap().services.is_empty());
+        if empty {
+            common::remove_protobuf_group(protobufs, ns, name);
+        }
+    } else if let Some(p) = common::find_protobuf(protobufs, ns, name.clone()) {
+        p.lock().unwrap().services.retain(|s| s.lock().unwrap().id != record.id);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+// A gossip message signed with the gossip secret.
+trait Signed: Message + Sized {
+    fn time(&self) -> u64;
+    fn signature(&mut self) -> &mut Vec<u8>;
+}
+
+macro_rules! signed {
+    ($($m:ty),*) => {$(
+        impl Signed for $m {
+            fn time(&self) -> u64 {
+                self.time
+            }
+
+            fn signature(&mut self) -> &mut Vec<u8> {
+                &mut self.signature
+            }
+        }
+    )*};
+}
+
+signed!(GossipRequest, GossipResponse, GossipPush);
+
+// Sign the message with an HMAC-SHA256 of its encoding without a signature.
+fn sign<M: Signed>(secret: &[u8], mut message: M) -> M {
+    message.signature().clear();
+    *message.signature() = hmac_sha256::HMAC::mac(message.encode_to_vec(), secret).to_vec();
+    message
+}
+
+fn verify<M: Signed>(secret: &[u8], mut message: M, now: u64) -> Result<M, String> {
+    let signature = std::mem::take(message.signature());
+    let expected = hmac_sha256::HMAC::mac(message.encode_to_vec(), secret);
+    if !admin::secret_matches(&signature, &expected) {
+        return Err("invalid gossip signature".to_string());
+    }
+    if now.abs_diff(message.time()) > MAX_SKEW {
+        return Err("gossip message too old".to_string());
+    }
+    Ok(message)
+}
+
+// This node in the gossip mesh.
+struct Mesh {
+    replica: Replica,
+    secret: Vec<u8>,
+}
+
+impl Mesh {
+    fn registry(&self) -> &'static Mutex<Protobufs> {
+        GDATA.get().unwrap()
+    }
+
+    fn encode(records: &[Record]) -> Vec<u8> {
+        serde_json::to_vec(records).unwrap_or_default()
+    }
+
+    fn decode(records: &[u8]) -> Result<Vec<Record>, String> {
+        serde_json::from_slice(records).map_err(|e| format!("invalid gossip records: {}", e))
+    }
+
+    // One round with a peer: send our versions, take the later records it
+    // answers with, push the records it wants.
+    async fn round(&self, client: &mut GossipClient<Channel>) -> Result<(), String> {
+        self.replica.refresh(self.registry(), now_millis());
+        let request = sign(&self.secret, GossipRequest {
+            node: self.replica.id.clone(),
+            time: now_millis(),
+            versions: self.replica.versions(),
+            signature: Vec::new(),
+        });
+        let response = client.exchange(request).await.map_err(|e| e.message().to_string())?.into_inner();
+        let response = verify(&self.secret, response, now_millis())?;
+        let remote = Mesh::decode(&response.records)?;
+        let taken = remote.len();
+        self.replica.merge(self.registry(), remote);
+        let records = self.replica.records(&response.wanted);
+        debug!(taken, sent = records.len(), "gossip round");
+        if records.is_empty() {
+            return Ok(());
+        }
+        let push = sign(&self.secret, GossipPush {
+            node: self.replica.id.clone(),
+            time: now_millis(),
+            records: Mesh::encode(&records),
+            signature: Vec::new(),
+        });
+        client.push(push).await.map_err(|e| e.message().to_string())?;
+        Ok(())
+    }
+}
+
+struct GossipService(Arc<Mesh>);
+
+#[tonic::async_trait]
+impl Gossip for GossipService {
+    async fn exchange(&self, request: Request<GossipRequest>) -> Result<Response<GossipResponse>, Status> {
+        let mesh = &self.0;
+        let request = verify(&mesh.secret, request.into_inner(), now_millis()).map_err(unauthenticated)?;
+        mesh.replica.refresh(mesh.registry(), now_millis());
+        let (records, wanted) = mesh.replica.compare(&request.versions);
+        debug!(node = %request.node, sent = records.len(), wanted = wanted.len(), "gossip from peer");
+        Ok(Response::new(sign(&mesh.secret, GossipResponse {
+            time: now_millis(),
+            records: Mesh::encode(&records),
+            wanted,
+            signature: Vec::new(),
+        })))
+    }
+
+    async fn push(&self, request: Request<GossipPush>) -> Result<Response<GossipPushResponse>, Status> {
+        let mesh = &self.0;
+        let request = verify(&mesh.secret, request.into_inner(), now_millis()).map_err(unauthenticated)?;
+        let remote = Mesh::decode(&request.records).map_err(Status::invalid_argument)?;
+        debug!(node = %request.node, records = remote.len(), "gossip push from peer");
+        mesh.replica.merge(mesh.registry(), remote);
+        Ok(Response::new(GossipPushResponse {}))
+    }
+}
+
+fn unauthenticated(e: String) -> Status {
+    warn!(error = %e, "gossip message refused");
+    Status::unauthenticated(e)
+}
+
+// Run a round with a random peer every interval.
+async fn run(mesh: Arc<Mesh>, peers: Vec<(String, GossipClient<Channel>)>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        let Some((peer, client)) = peers.choose(&mut rand::thread_rng()) else {
+            continue;
+        };
+        let mut client = client.clone();
+        let timeout = interval.max(Duration::from_secs(1));
+        let result = match tokio::time::timeout(timeout, mesh.round(&mut client)).await {
+            Ok(result) => result,
+            Err(_) => Err("timed out".to_string()),
+        };
+        match result {
+            Ok(()) => metrics::gossip_round("ok"),
+            Err(e) => {
+                debug!(peer = %peer, error = %e, "gossip round failed");
+                metrics::gossip_round("failed");
+            },
+        }
+    }
+}
+
+// Join the gossip mesh when gossip_address is configured. It cannot be
+// combined with clustering and needs gossip_secret, shared by all nodes.
+pub fn init(params: &HashMap<String, String>) -> Result<(), String> {
+    let Some(id) = params.get("gossip_address") else {
+        return Ok(());
+    };
+    if params.contains_key("cluster_address") {
+        return Err("gossip_address and cluster_address cannot both be set".to_string());
+    }
+    let secret = match params.get("gossip_secret") {
+        Some(s) if !s.is_empty() => s.as_bytes().to_vec(),
+        _ => return Err("gossip_secret must be set with gossip_address".to_string()),
+    };
+    let addr = id.parse().map_err(|e| format!("bad gossip address {}: {}", id, e))?;
+    let mut peers = Vec::new();
+    for peer in params.get("gossip_peers").map(|p| p.as_str()).unwrap_or("").split(',')
+        .map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let channel = Channel::from_shared(format!("http://{}", peer))
+            .map_err(|e| format!("bad gossip peer {}: {}", peer, e))?
+            .connect_lazy();
+        peers.push((peer.to_string(), GossipClient::new(channel)));
+    }
+    let interval = Duration::from_millis(config_number(params, "gossip_interval_ms", 1000).max(1));
+    let mesh = Arc::new(Mesh { replica: Replica::new(id.clone()), secret });
+    let service = GossipServer::new(GossipService(mesh.clone()));
+    tokio::spawn(async move {
+        if let Err(e) = Server::builder().add_service(service).serve(addr).await {
+            error!(error = %e, "gossip listener failed");
+        }
+    });
+    info!(address = %id, peers = peers.len(), "joined gossip mesh");
+    tokio::spawn(run(mesh, peers, interval));
+    Ok(())
+}
+
+// A round between two replicas as run over the network, with a as the node
+// starting it.
+#[cfg(test)]
+fn test_round(a: &Replica, ra: &Mutex<Protobufs>, b: &Replica, rb: &Mutex<Protobufs>, now: u64) {
+    a.refresh(ra, now);
+    b.refresh(rb, now);
+    let (newer, wanted) = b.compare(&a.versions());
+    a.merge(ra, newer);
+    b.merge(rb, a.records(&wanted));
+}
+
+#[test]
+fn test_gossip_converges() {
+    use crate::common::{add_protobuf, add_service, find_protobuf, remove_protobuf_group, secret_digest};
+    let (a, b) = (Replica::new("a".to_string()), Replica::new("b".to_string()));
+    let ra = Mutex::new(Protobufs { namespaces: HashMap::new() });
+    let rb = Mutex::new(Protobufs { namespaces: HashMap::new() });
+
+    // a registration on a reaches b, with the digest of its token
+    let id = {
+        let mut pa = ra.lock().unwrap();
+        add_protobuf(&mut pa, "dev", "p1".to_string()).unwrap();
+        let mut p = find_protobuf(&pa, "dev", "p1".to_string()).unwrap().lock().unwrap();
+        add_service(&mut p, "url1".to_string(), Some("tok".to_string())).unwrap();
+        p.services[0].lock().unwrap().id.clone()
+    };
+    test_round(&a, &ra, &b, &rb, 1000);
+    let first = a.records(&a.versions());
+    assert!(!serde_json::to_string(&first).unwrap().contains("\"tok\""), "token shipped");
+    {
+        let pb = rb.lock().unwrap();
+        let p = find_protobuf(&pb, "dev", "p1".to_string()).unwrap().lock().unwrap();
+        let svc = p.services[0].lock().unwrap();
+        assert_eq!((svc.id.as_str(), svc.url.as_str()), (id.as_str(), "url1"));
+        assert_eq!(svc.stk, Some(secret_digest("tok")));
+    }
+    b.refresh(&rb, 1100);
+    assert!(b.versions().iter().all(|v| v.node == "a" && v.ts == 1000), "applied records restamped");
+    // in sync, nothing is sent either way
+    let (newer, wanted) = b.compare(&a.versions());
+    assert!(newer.is_empty() && wanted.is_empty());
+
+    // both change the instance, the later change wins on both
+    let instance = |r: &Mutex<Protobufs>, f: &dyn Fn(&mut crate::Service)| {
+        let pr = r.lock().unwrap();
+        f(&mut find_protobuf(&pr, "dev", "p1".to_string()).unwrap().lock().unwrap().services[0].lock().unwrap());
+    };
+    instance(&ra, &|svc| svc.cdn = true);
+    a.refresh(&ra, 1500);
+    instance(&rb, &|svc| svc.ctr = 5);
+    test_round(&a, &ra, &b, &rb, 2000);
+    for r in [&ra, &rb] {
+        instance(r, &|svc| assert_eq!((svc.ctr, svc.cdn), (5, false)));
+    }
+
+    // a removal reaches b and older records do not bring the name back
+    remove_protobuf_group(&mut ra.lock().unwrap(), "dev", "p1");
+    test_round(&a, &ra, &b, &rb, 3000);
+    assert!(a.records(&a.versions()).iter().all(|r| !r.live()));
+    assert!(find_protobuf(&rb.lock().unwrap(), "dev", "p1".to_string()).is_none(), "removal not applied");
+    b.merge(&rb, first);
+    assert!(rb.lock().unwrap().namespaces.is_empty(), "stale record brought the name back");
+}
+
+#[test]
+fn test_gossip_merge_keeps_unobserved_change() {
+    use crate::common::{add_protobuf, add_service, find_protobuf};
+    let (a, b) = (Replica::new("a".to_string()), Replica::new("b".to_string()));
+    let ra = Mutex::new(Protobufs { namespaces: HashMap::new() });
+    let rb = Mutex::new(Protobufs { namespaces: HashMap::new() });
+    {
+        let mut pa = ra.lock().unwrap();
+        add_protobuf(&mut pa, "dev", "p1".to_string()).unwrap();
+        add_service(&mut find_protobuf(&pa, "dev", "p1".to_string()).unwrap().lock().unwrap(),
+                    "url1".to_string(), None).unwrap();
+    }
+    test_round(&a, &ra, &b, &rb, 1000);
+    let instance = |r: &Mutex<Protobufs>, f: &dyn Fn(&mut crate::Service)| {
+        let pr = r.lock().unwrap();
+        f(&mut find_protobuf(&pr, "dev", "p1".to_string()).unwrap().lock().unwrap().services[0].lock().unwrap());
+    };
+    // b changes the instance after its last refresh, a's record of an
+    // earlier change must not wipe it
+    instance(&ra, &|svc| svc.cdn = true);
+    a.refresh(&ra, 1100);
+    instance(&rb, &|svc| svc.ctr = 5);
+    b.merge(&rb, a.records(&a.versions()));
+    instance(&rb, &|svc| assert_eq!((svc.ctr, svc.cdn), (5, false)));
+    // the change is stamped at the next round and wins on both
+    test_round(&a, &ra, &b, &rb, 1200);
+    for r in [&ra, &rb] {
+        instance(r, &|svc| assert_eq!(svc.ctr, 5));
+    }
+}
+
+#[test]
+fn test_gossip_signature() {
+    let request = |time| GossipRequest { node: "a".to_string(), time, ..Default::default() };
+    let signed = sign(b"s3cret", request(1000));
+    assert!(verify(b"s3cret", signed.clone(), 1500).is_ok());
+    assert!(verify(b"other", signed.clone(), 1500).is_err(), "wrong secret accepted");
+    assert!(verify(b"s3cret", signed.clone(), 1000 + MAX_SKEW + 1).is_err(), "old message accepted");
+    let mut forged = signed;
+    forged.node = "b".to_string();
+    assert!(verify(b"s3cret", forged, 1500).is_err(), "changed message accepted");
+    assert!(verify(b"s3cret", request(1000), 1000).is_err(), "unsigned message accepted");
+}
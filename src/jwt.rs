@@ -17,22 +17,35 @@
 
 use std::fs::read_to_string;
 use jwt_simple::prelude::*;
+use crate::common;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MyAdditionalData {
     pub user_name: String,
     pub user_is_admin: bool,
     pub user_country: String,
+    #[serde(default)]
+    pub instance_id: String,    // registered instance, empty for other tokens
+    #[serde(default)]
+    pub namespace: String,      // namespace the token is good for, empty for the default
+}
+
+// Longest lifetime of a token the registry issues, the registration token's.
+// Tokens issued longer ago are refused whatever their expiry says.
+pub fn max_validity() -> Duration {
+    Duration::from_hours(12)
 }
 
 // Create the jwt from the key pair
-pub fn create_token(kp: &RS256KeyPair, username: String, subject: String, is_admin: bool,
-    duration: Duration) -> Result<String, String> {
+pub fn create_token(kp: &RS256KeyPair, username: String, subject: String, instance_id: String,
+    namespace: String, is_admin: bool, duration: Duration) -> Result<String, String> {
 
     let my_additional_data = MyAdditionalData {
         user_name: username,
         user_is_admin: is_admin,
         user_country: "US".to_string(),
+        instance_id,
+        namespace,
     };
 
     let mut options = VerificationOptions::default();
@@ -42,11 +55,19 @@ pub fn create_token(kp: &RS256KeyPair, username: String, subject: String, is_adm
     // and/or they will be valid within 15 minutes.
     // Note that 15 minutes is the default, since it is very common for clocks to be slightly off.
     options.time_tolerance = Some(Duration::from_mins(15));
-    // Reject tokens if they were issued more than 1 hour ago
-    options.max_validity = Some(Duration::from_mins(60));
+    // Reject tokens issued longer ago than the longest lifetime the registry gives
+    options.max_validity = Some(max_validity());
 
-    let claims = Claims::with_custom_claims(my_additional_data,
-                                            duration).with_subject(subject);
+    // A random id keeps tokens issued within the same second distinct, so a
+    // replaced registration token can be told apart from its successor.
+    let jwt_id = format!("{:016x}", common::random_u64());
+    let mut claims = Claims::with_custom_claims(my_additional_data,
+                                                duration).with_subject(subject).with_jwt_id(jwt_id);
+    // the registry clock, so nodes replaying a write issue the same token
+    let now = unix_time(common::now());
+    claims.issued_at = Some(now);
+    claims.invalid_before = Some(now);
+    claims.expires_at = Some(now + duration);
     let token = match kp.sign(claims) {
         Ok(v) => v,
         Err(e) => {
@@ -71,8 +92,9 @@ pub fn validate_token(kp: &RS256KeyPair, token: String) -> Result<JWTClaims<MyAd
     // and/or they will be valid within 15 minutes.
     // Note that 15 minutes is the default, since it is very common for clocks to be slightly off.
     options.time_tolerance = Some(Duration::from_mins(15));
-    // Reject tokens if they were issued more than 1 hour ago
-    options.max_validity = Some(Duration::from_mins(60));
+    // Reject tokens issued longer ago than the longest lifetime the registry gives
+    options.max_validity = Some(max_validity());
+    options.artificial_time = Some(unix_time(common::now()));
 
     let pk = kp.public_key();
     let x = match pk.verify_token::<MyAdditionalData>(token.as_str(), Some(options)) {
@@ -85,6 +107,10 @@ pub fn validate_token(kp: &RS256KeyPair, token: String) -> Result<JWTClaims<MyAd
     return Ok(x)
 }
 
+fn unix_time(t: std::time::SystemTime) -> UnixTimeStamp {
+    Duration::from_secs(t.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
 // Load the PEM from disk and return the public key.
 pub fn load_pem(file: String) -> Result<RS256KeyPair, String> {
     let pem = match read_to_string(&file) {
@@ -104,7 +130,7 @@ fn test_loadpem () {
     let kpp = kp.clone();
     let duration = Duration::from_mins(10);
     let token = create_token(&kp, "bill".to_string(),
-                                  "mysubject".to_string(), false, duration).unwrap();
+                                  "mysubject".to_string(), "".to_string(), "".to_string(), false, duration).unwrap();
     let r = validate_token(&kpp, token);
     let rr = r.clone();
     if r.is_err() {
@@ -113,6 +139,19 @@ fn test_loadpem () {
     }
 }
 
+#[test]
+fn test_token_valid_for_its_lifetime() {
+    let kp = load_pem("mykey.pem".to_string()).unwrap();
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let hours = |h: u64| h * 3600 * 1000;
+    let issue = |ago: u64, lifetime: Duration| common::replay(now - ago, 1, false, || create_token(
+        &kp, "url1".to_string(), "proto1".to_string(), "".to_string(), "".to_string(), false, lifetime).unwrap());
+    // a registration token still validates hours after it was issued
+    assert!(validate_token(&kp, issue(hours(2), max_validity())).is_ok());
+    assert!(validate_token(&kp, issue(hours(11), max_validity())).is_ok());
+    assert!(validate_token(&kp, issue(hours(13), Duration::from_hours(14))).is_err(), "max validity not applied");
+}
+
 #[test]
 fn test_validate_token() {
     // first create the keypair and make the token
@@ -121,7 +160,7 @@ fn test_validate_token() {
     let k2 = kp.clone();
 
     let r = create_token(&kp, "myuser".to_string(),
-                         "mysubject".to_string(), false,
+                         "mysubject".to_string(), "0123456789abcdef".to_string(), "dev".to_string(), false,
                          Duration::from_mins(1)
                         );
     if r.is_err() {
@@ -134,6 +173,8 @@ fn test_validate_token() {
     if x.is_ok() {
         let z = x.unwrap();
         assert_eq!(z.custom.user_name, "myuser".to_string(), "mismatched user");
+        assert_eq!(z.custom.instance_id, "0123456789abcdef", "mismatched instance id");
+        assert_eq!(z.custom.namespace, "dev", "mismatched namespace");
     }
     else {
         println!("error in validation {}", x.unwrap_err());
@@ -0,0 +1,213 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Rate limits on calls into the registry, per peer address and per token
+// subject, so one client looping on auth or find cannot saturate the registry
+// lock. Every key has a token bucket refilled at rate_limit_peer or
+// rate_limit_subject calls per second from setting.toml, holding up to
+// rate_limit_burst calls. The peer limit is checked before a handler runs,
+// the subject limit when the handler has validated the token and before it
+// takes the registry lock. Excess calls are rejected with RESOURCE_EXHAUSTED.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+use jwt_simple::prelude::JWTClaims;
+use once_cell::sync::OnceCell;
+use tonic::Status;
+use crate::{common, config_number, jwt, metrics};
+
+static PEER_LIMIT: OnceCell<RateLimiter> = OnceCell::new();
+static SUBJECT_LIMIT: OnceCell<RateLimiter> = OnceCell::new();
+
+// Buckets kept, the least recently used one is dropped for a new key.
+const MAX_BUCKETS: usize = 10000;
+
+thread_local! {
+    // Set when the subject limit rejects the token of the running handler.
+    static SUBJECT_LIMITED: Cell<bool> = const { Cell::new(false) };
+}
+
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+    used: u64,      // position in the use order
+}
+
+#[derive(Default)]
+struct Buckets {
+    keys: HashMap<String, Bucket>,
+    order: BTreeMap<u64, String>,   // keys by last use, oldest first
+    uses: u64,
+}
+
+pub struct RateLimiter {
+    rate: f64,      // calls per second
+    burst: f64,     // calls allowed at once
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate as f64,
+            burst: burst.max(rate).max(1) as f64,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    // Take one call from the bucket of the key. Returns false when it is empty.
+    pub fn allow(&self, key: &str) -> bool {
+        self.allow_at(key, Instant::now())
+    }
+
+    fn allow_at(&self, key: &str, now: Instant) -> bool {
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        buckets.uses += 1;
+        let used = buckets.uses;
+        match buckets.keys.get_mut(key) {
+            Some(bucket) => {
+                buckets.order.remove(&bucket.used);
+                bucket.used = used;
+            },
+            None => {
+                if buckets.keys.len() >= MAX_BUCKETS
+                    && let Some((_, oldest)) = buckets.order.pop_first() {
+                    buckets.keys.remove(&oldest);
+                }
+                buckets.keys.insert(key.to_string(), Bucket { tokens: self.burst, last: now, used });
+            },
+        }
+        buckets.order.insert(used, key.to_string());
+        let bucket = buckets.keys.get_mut(key).unwrap();
+        let elapsed = now.duration_since(bucket.last).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.last = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+// Set up the configured limits. A rate of 0 leaves that limit off.
+pub fn init(params: &HashMap<String, String>) {
+    let burst = config_number(params, "rate_limit_burst", 0);
+    let peer = config_number(params, "rate_limit_peer", 0);
+    if peer > 0 {
+        tracing::info!(rate = peer, burst, "rate limiting calls per peer address");
+        let _ = PEER_LIMIT.set(RateLimiter::new(peer, burst));
+    }
+    let subject = config_number(params, "rate_limit_subject", 0);
+    if subject > 0 {
+        tracing::info!(rate = subject, burst, "rate limiting calls per token subject");
+        let _ = SUBJECT_LIMIT.set(RateLimiter::new(subject, burst));
+    }
+}
+
+// Check a call against the limit of its peer address.
+#[allow(clippy::result_large_err)]
+pub fn check(method: &str, peer: Option<SocketAddr>) -> Result<(), Status> {
+    if let (Some(limiter), Some(addr)) = (PEER_LIMIT.get(), peer)
+        && !limiter.allow(&addr.ip().to_string()) {
+        return Err(Status::resource_exhausted(limited(method, "peer")));
+    }
+    Ok(())
+}
+
+// Check a token the handler has validated against the limit of its subject.
+// A rejection is noted for subject_limited, the handler stops on the error.
+// Writes replayed from the cluster log are not limited, every node has to
+// apply them alike.
+pub fn check_subject(method: &str, claim: &JWTClaims<jwt::MyAdditionalData>) -> Result<(), String> {
+    let limiter = match SUBJECT_LIMIT.get() {
+        Some(l) if !common::replaying() => l,
+        _ => return Ok(()),
+    };
+    let key = format!("{}/{}", common::namespace_name(&claim.custom.namespace),
+                      claim.subject.as_deref().unwrap_or_default());
+    if limiter.allow(&key) {
+        return Ok(());
+    }
+    SUBJECT_LIMITED.with(|l| l.set(true));
+    Err(limited(method, "subject"))
+}
+
+// Run a handler. Returns the rate limit message instead of its response when
+// the subject limit rejected the token it presented.
+pub fn subject_limited<T>(f: impl FnOnce() -> T) -> Result<T, String> {
+    SUBJECT_LIMITED.with(|l| l.set(false));
+    let r = f();
+    match SUBJECT_LIMITED.with(|l| l.replace(false)) {
+        true => Err("subject rate limit exceeded".to_string()),
+        false => Ok(r),
+    }
+}
+
+fn limited(method: &str, limit: &str) -> String {
+    metrics::rate_limited(method, limit);
+    tracing::debug!(method, limit, "call rate limited");
+    format!("{} rate limit exceeded", limit)
+}
+
+// Most entries allowed by a cap from setting.toml, 0 for no cap.
+pub fn cap(key: &str) -> usize {
+    common::setting(key).and_then(|v| v.parse().ok()).unwrap_or(0)
+}
+
+#[test]
+fn test_rate_limiter() {
+    let limiter = RateLimiter::new(2, 3);
+    let start = Instant::now();
+    for _ in 0..3 {
+        assert!(limiter.allow_at("a", start));
+    }
+    assert!(!limiter.allow_at("a", start), "burst exceeded");
+    assert!(limiter.allow_at("b", start), "keys share a bucket");
+    // two calls per second come back
+    let later = start + std::time::Duration::from_millis(500);
+    assert!(limiter.allow_at("a", later));
+    assert!(!limiter.allow_at("a", later));
+    let much_later = start + std::time::Duration::from_secs(60);
+    for _ in 0..3 {
+        assert!(limiter.allow_at("a", much_later));
+    }
+    assert!(!limiter.allow_at("a", much_later), "bucket filled beyond the burst");
+}
+
+#[test]
+fn test_rate_limiter_drops_oldest_bucket() {
+    let limiter = RateLimiter::new(1, 1);
+    let start = Instant::now();
+    assert!(limiter.allow_at("first", start));
+    assert!(limiter.allow_at("second", start));
+    for i in 2..MAX_BUCKETS {
+        assert!(limiter.allow_at(&format!("key{}", i), start));
+    }
+    // "first" was used again, so the new key drops "second"
+    assert!(!limiter.allow_at("first", start));
+    assert!(limiter.allow_at("new", start));
+    let buckets = limiter.buckets.lock().unwrap();
+    assert_eq!(buckets.keys.len(), MAX_BUCKETS);
+    assert_eq!(buckets.order.len(), MAX_BUCKETS);
+    assert!(buckets.keys.contains_key("first"));
+    assert!(!buckets.keys.contains_key("second"));
+}
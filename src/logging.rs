@@ -0,0 +1,151 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Structured logging. Log lines go to stdout through tracing, as text or as
+// JSON, filtered by log_level from setting.toml. Every RPC runs inside a span
+// carrying the method, the namespace, the protobuf name and the subject of the
+// token used. Tokens themselves are never logged. When span export is
+// configured the spans also go to OpenTelemetry, parented on the caller's
+// trace context.
+
+use std::collections::HashMap;
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use tonic::metadata::MetadataMap;
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+use crate::common;
+
+// Output format of the log lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    TEXT = 0,  // human readable lines
+    JSON = 1,  // one JSON object per line
+}
+
+impl LogFormat {
+    pub fn from_name(name: &str) -> Option<LogFormat> {
+        match name.to_lowercase().as_str() {
+            "text" => Some(LogFormat::TEXT),
+            "json" => Some(LogFormat::JSON),
+            _ => None,
+        }
+    }
+}
+
+// Install the global subscriber. log_level takes a level such as "debug" or
+// filter directives like "reg_server=debug,tonic=info"; info when not set.
+// log_format is text or json, text when not set. Spans are exported through
+// the tracer provider when there is one.
+pub fn init(params: &HashMap<String, String>, provider: Option<&TracerProvider>) {
+    let level = params.get("log_level").cloned().unwrap_or("info".to_string());
+    let (filter, bad_level) = match EnvFilter::try_new(&level) {
+        Ok(f) => (f, None),
+        Err(e) => (EnvFilter::new("info"), Some(e)),
+    };
+    let format = params.get("log_format").map(|f| (f.clone(), LogFormat::from_name(f)));
+
+    let fmt = match format {
+        Some((_, Some(LogFormat::JSON))) => tracing_subscriber::fmt::layer().json().boxed(),
+        _ => tracing_subscriber::fmt::layer().boxed(),
+    };
+    let otel = provider.map(|p| tracing_opentelemetry::layer().with_tracer(p.tracer("reg-server")));
+    let r = tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt)
+        .with(otel)
+        .try_init();
+    if let Err(e) = r {
+        eprintln!("Failed to initialize logging {}", e);
+        return;
+    }
+    if let Some(e) = bad_level {
+        tracing::warn!(log_level = %level, error = %e, "invalid log_level, using info");
+    }
+    if let Some((name, None)) = format {
+        tracing::warn!(log_format = %name, "unknown log_format, using text");
+    }
+}
+
+// Span for one RPC, continuing the trace context in the request metadata.
+// The protobuf name is left out when the request does not carry one, the
+// token subject and user are recorded once the token is valid.
+pub fn rpc_span(method: &str, namespace: &str, protobuf_name: &str, metadata: &MetadataMap) -> Span {
+    let namespace = common::namespace_name(namespace);
+    let span = tracing::info_span!("rpc", "otel.name" = method, method, namespace, protobuf_name = Empty,
+                                  subject = Empty, user = Empty);
+    if !protobuf_name.is_empty() {
+        span.record("protobuf_name", protobuf_name);
+    }
+    span.set_parent(extract(metadata));
+    span
+}
+
+struct MetadataExtractor<'a>(&'a MetadataMap);
+
+impl Extractor for MetadataExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().filter_map(|k| match k {
+            tonic::metadata::KeyRef::Ascii(k) => Some(k.as_str()),
+            tonic::metadata::KeyRef::Binary(_) => None,
+        }).collect()
+    }
+}
+
+// Trace context sent by the caller, empty when there is none.
+pub fn extract(metadata: &MetadataMap) -> Context {
+    TraceContextPropagator::new().extract(&MetadataExtractor(metadata))
+}
+
+#[test]
+fn test_log_format_names() {
+    assert_eq!(LogFormat::from_name("json"), Some(LogFormat::JSON));
+    assert_eq!(LogFormat::from_name("TEXT"), Some(LogFormat::TEXT));
+    assert_eq!(LogFormat::from_name("xml"), None);
+}
+
+#[test]
+fn test_inject_and_extract() {
+    use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+
+    let trace_id = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+    let span_id = SpanId::from_hex("00f067aa0ba902b7").unwrap();
+    let sc = SpanContext::new(trace_id, span_id, TraceFlags::SAMPLED, true, TraceState::default());
+    let cx = Context::new().with_remote_span_context(sc);
+
+    let mut metadata = MetadataMap::new();
+    crate::propagation::inject(&cx, &mut metadata);
+    assert_eq!(metadata.get("traceparent").unwrap().to_str().unwrap(),
+               "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+
+    let extracted = extract(&metadata);
+    let span = extracted.span();
+    assert_eq!(span.span_context().trace_id(), trace_id);
+    assert_eq!(span.span_context().span_id(), span_id);
+    assert!(!extract(&MetadataMap::new()).has_active_span(), "context from empty metadata");
+}
@@ -0,0 +1,217 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Prometheus metrics. The collectors are registered with the default
+// prometheus registry and served as text from an optional HTTP listener
+// configured by metrics_address in setting.toml.
+
+use std::time::Instant;
+use axum::routing::get;
+use axum::Router;
+use once_cell::sync::Lazy;
+use prometheus::{Encoder, Histogram, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder};
+use prometheus::{register_histogram, register_histogram_vec, register_int_counter_vec,
+                 register_int_gauge, register_int_gauge_vec};
+use crate::registry::StatusPacket;
+use crate::GDATA;
+
+static RPC_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_rpc_requests_total",
+        "Registry RPCs handled by method and status code", &["method", "code"]).unwrap()
+});
+
+static RPC_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!("registry_rpc_duration_seconds",
+        "Registry RPC latency by method and status code", &["method", "code"]).unwrap()
+});
+
+static PROTOBUFS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("registry_protobufs", "Registered protobuf names by namespace",
+        &["namespace"]).unwrap()
+});
+
+static INSTANCES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("registry_instances",
+        "Registered instances by namespace, protobuf name and health",
+        &["namespace", "protobuf_name", "health"]).unwrap()
+});
+
+static KEEPALIVE_LAG: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!("registry_keepalive_lag_seconds",
+        "Seconds between consecutive keepalives of an instance",
+        vec![1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0]).unwrap()
+});
+
+static EVICTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_evictions_total",
+        "Instances evicted from the registry by reason", &["reason"]).unwrap()
+});
+
+static TOKEN_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_token_validation_failures_total",
+        "Tokens that failed validation", &["method"]).unwrap()
+});
+
+static RATE_LIMITED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_rate_limited_total",
+        "Calls rejected by a rate limit, by method and limit", &["method", "limit"]).unwrap()
+});
+
+static CLUSTER_LEADER: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("registry_cluster_leader",
+        "1 while this node is the cluster leader").unwrap()
+});
+
+static GOSSIP_ROUNDS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_gossip_rounds_total",
+        "Gossip exchanges started by this node, by result", &["result"]).unwrap()
+});
+
+static FIND_HANDOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("registry_find_handouts_total",
+        "Instances handed out by FIND", &["namespace", "protobuf_name", "service_url"]).unwrap()
+});
+
+// Name of a status packet code for metric labels. Keep in sync with StatusCodes.
+fn code_name(code: i32) -> &'static str {
+    match code {
+        0 => "SUCCESS",
+        1 => "NOT_FOUND",
+        2 => "DUPLICATE",
+        3 => "BADTOKEN",
+        4 => "AUTHERROR",
+        5 => "SERVERROR",
+        6 => "INCOMPATIBLE",
+        7 => "EXHAUSTED",
+        _ => "UNKNOWN",
+    }
+}
+
+// Record an RPC that answered with a status packet. No packet means success.
+pub fn observe_rpc(method: &str, start: Instant, status: &Option<StatusPacket>) {
+    let code = match status {
+        Some(s) => code_name(s.code),
+        None => code_name(0),
+    };
+    observe_rpc_code(method, start, code);
+}
+
+// Record an RPC under an explicit code, used when it fails with a gRPC status.
+pub fn observe_rpc_code(method: &str, start: Instant, code: &str) {
+    RPC_REQUESTS.with_label_values(&[method, code]).inc();
+    RPC_DURATION.with_label_values(&[method, code]).observe(start.elapsed().as_secs_f64());
+}
+
+pub fn keepalive_lag(seconds: f64) {
+    KEEPALIVE_LAG.observe(seconds);
+}
+
+pub fn eviction(reason: &str) {
+    EVICTIONS.with_label_values(&[reason]).inc();
+}
+
+pub fn token_failure(method: &str) {
+    TOKEN_FAILURES.with_label_values(&[method]).inc();
+}
+
+pub fn rate_limited(method: &str, limit: &str) {
+    RATE_LIMITED.with_label_values(&[method, limit]).inc();
+}
+
+pub fn cluster_leader(leader: bool) {
+    CLUSTER_LEADER.set(leader as i64);
+}
+
+pub fn gossip_round(result: &str) {
+    GOSSIP_ROUNDS.with_label_values(&[result]).inc();
+}
+
+pub fn find_handout(namespace: &str, protobuf_name: &str, service_url: &str) {
+    FIND_HANDOUTS.with_label_values(&[namespace, protobuf_name, service_url]).inc();
+}
+
+// Update the registry gauges from the current state.
+fn refresh_gauges() {
+    let gdata = match GDATA.get() {
+        Some(g) => g,
+        None => return,
+    };
+    let protobufs = gdata.lock().unwrap();
+    // names that went away must not keep their last value
+    PROTOBUFS.reset();
+    INSTANCES.reset();
+    for (ns, protomap) in protobufs.namespaces.iter() {
+        PROTOBUFS.with_label_values(&[ns.as_str()]).set(protomap.len() as i64);
+        for m in protomap.values() {
+            let protobuf = m.lock().unwrap();
+            for s in protobuf.services.iter() {
+                let health = format!("{:?}", s.lock().unwrap().hst);
+                INSTANCES.with_label_values(&[ns.as_str(), protobuf.name.as_str(), health.as_str()]).inc();
+            }
+        }
+    }
+}
+
+// Render all metrics in the prometheus text format.
+pub fn render() -> String {
+    refresh_gauges();
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return format!("# failed to encode metrics: {}\n", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+async fn metrics_handler() -> String {
+    render()
+}
+
+// Serve /metrics on the configured address until the process exits.
+pub async fn serve(addr: String) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!(address = %addr, error = %e, "failed to start metrics listener");
+            return;
+        },
+    };
+    let app = Router::new().route("/metrics", get(metrics_handler));
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!(error = %e, "metrics listener stopped");
+    }
+}
+
+#[test]
+fn test_render_metrics() {
+    let start = Instant::now();
+    observe_rpc("find", start, &None);
+    observe_rpc_code("regs", start, "UNAVAILABLE");
+    token_failure("find");
+    rate_limited("auth", "peer");
+    eviction("health_check");
+    find_handout("default", "proto1", "url1");
+    keepalive_lag(3.0);
+    let text = render();
+    assert!(text.contains("registry_rpc_requests_total{code=\"SUCCESS\",method=\"find\"}"));
+    assert!(text.contains("registry_rpc_duration_seconds_bucket"));
+    assert!(text.contains("registry_token_validation_failures_total{method=\"find\"}"));
+    assert!(text.contains("registry_rate_limited_total{limit=\"peer\",method=\"auth\"}"));
+    assert!(text.contains("registry_evictions_total{reason=\"health_check\"}"));
+    assert!(text.contains("registry_find_handouts_total{namespace=\"default\",protobuf_name=\"proto1\",service_url=\"url1\"}"));
+    assert!(text.contains("registry_keepalive_lag_seconds_count"));
+}
@@ -0,0 +1,56 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// W3C trace context injection into gRPC metadata, so calls into the registry
+// stay part of the caller's trace. The server side extraction is in logging.
+
+use opentelemetry::propagation::{Injector, TextMapPropagator};
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::Context;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use tonic::metadata::{MetadataKey, MetadataMap, MetadataValue};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+struct MetadataInjector<'a>(&'a mut MetadataMap);
+
+impl Injector for MetadataInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        let key = MetadataKey::from_bytes(key.as_bytes());
+        let value = MetadataValue::try_from(value.as_str());
+        if let (Ok(k), Ok(v)) = (key, value) {
+            self.0.insert(k, v);
+        }
+    }
+}
+
+// Add the traceparent and tracestate entries for the context.
+pub fn inject(cx: &Context, metadata: &mut MetadataMap) {
+    TraceContextPropagator::new().inject_context(cx, &mut MetadataInjector(metadata));
+}
+
+// Client interceptor injecting the context of the current tracing span, or
+// the current OpenTelemetry context when the span is not traced. The
+// signature is the one tonic expects from an interceptor.
+#[allow(clippy::result_large_err)]
+pub fn inject_trace_context(mut request: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
+    let mut cx = tracing::Span::current().context();
+    if !cx.has_active_span() {
+        cx = Context::current();
+    }
+    inject(&cx, request.metadata_mut());
+    Ok(request)
+}
@@ -14,13 +14,15 @@
  * limitations under the License.
  *
  */
-use jwt_simple::prelude::Duration;
-use crate::{common, jwt, registry, Protobuf, GDATA};
-use crate::common::{find_protobuf, get_keypair, make_status_packet};
-use crate::registry::{DeRegisterRequest, DeRegisterResponse, FindProviderRequest, FindProviderResponse, KeepAliveResponse, KeepaliveReport};
+use std::time::Instant;
+use semver::{Version, VersionReq};
+use crate::{audit, balancer, common, compat, jwt, limits, metrics, registry, schemas, Protobuf, GDATA};
+use crate::common::{find_protobuf, make_status_packet};
+use crate::registry::{DeRegisterRequest, DeRegisterResponse, FindProviderInstance, FindProviderRequest, FindProviderResponse, KeepAliveResponse, KeepaliveReport, KeepaliveUpdate};
 
-// Handle protobuf registration. The protobuf name ans service url are encoded in
-// the jwt token so token validation can provide this information when deregistering.
+// Handle protobuf registration. The protobuf name, service url, instance id and
+// namespace are encoded in the jwt token so token validation can provide this
+// information when deregistering.
 pub fn handle_register(req: &registry::RegisterRequest) -> registry::RegisterResponse {
 
     let name1 = req.protobuf_name.to_string();
@@ -29,51 +31,247 @@ pub fn handle_register(req: &registry::RegisterRequest) -> registry::RegisterRes
     let url1 = req.protobuf_url.to_string();
     let url2 = url1.clone();
 
-    // username contains the url
-    // subject contains the protobuf name.
-    let token = common::make_token(url2.to_string(), name2.to_string(),
-                                   false, Duration::from_hours(12));
+    let ns = match common::check_namespace(&req.namespace) {
+        Ok(ns) => ns,
+        Err(e) => return register_error(common::make_status_packet(common::StatusEnum::SERVERROR, e)),
+    };
+
+    // A version sent with the registration must be valid semver.
+    if !req.version.is_empty() {
+        if let Err(e) = Version::parse(&req.version) {
+            let msg = format!("invalid version '{}': {}", req.version, e);
+            let s = common::make_status_packet(common::StatusEnum::SERVERROR, msg);
+            return register_error(s);
+        }
+    }
+
+    // A schema sent with the registration must be a valid descriptor set.
+    if !req.file_descriptor_set.is_empty() {
+        if let Err(e) = schemas::decode_schema(&req.file_descriptor_set) {
+            let s = common::make_status_packet(common::StatusEnum::SERVERROR, e);
+            return register_error(s);
+        }
+    }
 
     let mut protobufs = GDATA.get().unwrap().lock().unwrap();
-    let protobuf = common::find_protobuf(&protobufs, name1);
+    // instances of the namespace are counted before a protobuf is locked
+    let count = common::instance_count(&protobufs, &ns);
+    let protobuf = common::find_protobuf(&protobufs, &ns, name1);
     if protobuf.is_none() {
         // protobuf does not exist.
-        let r = common::add_protobuf(&mut protobufs, name2);
+        let names = protobufs.namespaces.get(&ns).map_or(0, |m| m.len());
+        let limited = over_quota(&ns, count)
+            .or_else(|| over_cap("max_protobufs_per_namespace", names, format!("namespace {}", ns), "protobuf names"));
+        if let Some(s) = limited {
+            return register_error(s);
+        }
+        let r = common::add_protobuf(&mut protobufs, &ns, name2);
         if r.is_err() {
             let s = common::make_status_packet(common::StatusEnum::SERVERROR, r.unwrap_err());
-            let rsp = registry::RegisterResponse {
-                token: "".to_string(),
-                status: Some(s),
-            };
-            return rsp;
+            return register_error(s);
         }
     }
     // Refetch protobuf incase it didn't exist and was just created.
-    let tmpprot = common::find_protobuf(&protobufs, name3);
+    let tmpprot = common::find_protobuf(&protobufs, &ns, name3);
     let mut protobuf = tmpprot.unwrap().lock().unwrap();
-    let token3 = token.clone();
-    let r = common::add_service(&mut protobuf, url2, token);
-    if r.is_err() {
-        let s = common::make_status_packet(common::StatusEnum::SERVERROR, r.unwrap_err());
-        let r = registry::RegisterResponse {
-            token: "".to_string(),
-            status: Some(s),
-        };
-        return r;
+    // A schema sent with the registration must not break the schemas of the
+    // instances already registered under this name.
+    if !req.file_descriptor_set.is_empty() {
+        let issues = compat::check_registered(&protobuf, &req.file_descriptor_set);
+        if !issues.is_empty() {
+            let msg = format!("incompatible schema: {}", issues.join("; "));
+            let s = common::make_status_packet(common::StatusEnum::INCOMPATIBLE, msg);
+            return register_error(s);
+        }
     }
+    let existing = match existing_instance(&protobuf, req) {
+        Ok(e) => e,
+        Err(msg) => {
+            let s = common::make_status_packet(common::StatusEnum::DUPLICATE, msg);
+            return register_error(s);
+        },
+    };
+    // a new instance must fit the quota of the namespace and the cap on
+    // instances of a protobuf
+    if existing.is_none() {
+        let holder = format!("protobuf {}", req.protobuf_name);
+        let limited = over_quota(&ns, count)
+            .or_else(|| over_cap("max_instances_per_protobuf", protobuf.services.len(), holder, "instances"));
+        if let Some(s) = limited {
+            return register_error(s);
+        }
+    }
+
+    // username contains the url
+    // subject contains the protobuf name.
+    // instance_id contains the id of the instance, kept by a re-registration.
+    // namespace contains the namespace the token is good in.
+    let id = match existing {
+        Some(idx) => protobuf.services[idx].lock().unwrap().id.clone(),
+        None => common::new_instance_id(),
+    };
+    // the longest lifetime token validation allows, so the expiry in the report holds
+    let ttl = jwt::max_validity();
+    let token = match common::make_token(url1, req.protobuf_name.clone(), id.clone(), ns.clone(), false, ttl) {
+        Some(t) => t,
+        None => {
+            let s = common::make_status_packet(common::StatusEnum::BADTOKEN,
+                                               "failed to create jwt token".to_string());
+            return register_error(s);
+        },
+    };
+    let idx = match existing {
+        Some(idx) => {
+            let mut svc = protobuf.services[idx].lock().unwrap();
+            common::reset_service(&mut svc, Some(token.clone()));
+            svc.url = url2;
+            idx
+        },
+        None => {
+            let m = common::make_service(url2, Some(token.clone()));
+            m.lock().unwrap().id = id.clone();
+            protobuf.services.push(m);
+            protobuf.services.len() - 1
+        },
+    };
+    let mut schema_version = 0;
+    if !req.file_descriptor_set.is_empty() {
+        schema_version = schemas::store_schema(&mut protobuf, &req.file_descriptor_set);
+    }
+    // Attach the registration details to the service just added.
+    let mut svc = protobuf.services[idx].lock().unwrap();
+    svc.ikey = common::secret_digest(&req.instance_key);
+    svc.meta = req.metadata.clone();
+    svc.sver = schema_version;
+    svc.ver = req.version.clone();
+    svc.tke = Some(common::now() + std::time::Duration::from_secs(ttl.as_secs()));
+    let replaced = existing.is_some();
+    tracing::info!(namespace = %ns, protobuf_name = %req.protobuf_name, url = %req.protobuf_url,
+                   instance_id = %id, version = %req.version, schema_version, replaced, "registered instance");
+    audit::record(audit::AuditEvent {
+        event: "register",
+        namespace: ns,
+        protobuf_name: req.protobuf_name.clone(),
+        url: req.protobuf_url.clone(),
+        instance_id: id.clone(),
+        user: req.protobuf_url.clone(),
+        token_id: audit::token_id(&token),
+        reason: if replaced { "replaced previous registration".to_string() } else { "".to_string() },
+        ..Default::default()
+    });
     let rsp = registry::RegisterResponse {
-        token: token3.unwrap(),
+        token,
         status: None,
+        schema_version,
+        instance_id: id,
     };
     rsp
 }
 
+// The instance a registration takes over, None for a new instance. An
+// instance id from an earlier registration names the instance, which may move
+// to a new url. Otherwise a url registered again, as a provider does after a
+// restart, takes over the instance registered there only when it sends the
+// instance key that instance registered with. A url registered as shared
+// always gets a new instance. Returns an error when the url belongs to an
+// instance the caller has not shown to own.
+fn existing_instance(protobuf: &Protobuf, req: &registry::RegisterRequest) -> Result<Option<usize>, String> {
+    let by_id = match req.instance_id.is_empty() {
+        true => None,
+        false => common::find_instance(protobuf, &req.instance_id, "").ok(),
+    };
+    if req.shared_url {
+        return Ok(by_id);
+    }
+    let by_url = protobuf.services.iter().enumerate()
+        .filter(|(_, s)| s.lock().unwrap().url == req.protobuf_url)
+        .map(|(idx, _)| idx)
+        .collect::<Vec<usize>>();
+    if let Some(idx) = by_id {
+        if by_url.iter().any(|u| *u != idx) {
+            return Err("url is registered by another instance".to_string());
+        }
+        return Ok(Some(idx));
+    }
+    match by_url.as_slice() {
+        [] => Ok(None),
+        [idx] => {
+            let svc = protobuf.services[*idx].lock().unwrap();
+            if svc.ikey.is_empty() || svc.ikey != common::secret_digest(&req.instance_key) {
+                return Err("url is already registered, give the instance id or instance key to replace it".to_string());
+            }
+            Ok(Some(*idx))
+        },
+        _ => Err("url is shared by several instances, give the instance id".to_string()),
+    }
+}
+
+#[test]
+fn test_existing_instance() {
+    let m = common::make_protobuf(&"proto1".to_string());
+    let mut p = m.lock().unwrap();
+    common::add_service(&mut p, "url1".to_string(), None).unwrap();
+    let id = p.services[0].lock().unwrap().id.clone();
+    let register = |key: &str, id: &str| registry::RegisterRequest {
+        protobuf_name: "proto1".to_string(),
+        protobuf_url: "url1".to_string(),
+        instance_key: key.to_string(),
+        instance_id: id.to_string(),
+        ..Default::default()
+    };
+    // without a key on either side nothing shows the caller owns the url
+    assert!(existing_instance(&p, &register("", "")).is_err());
+    assert!(existing_instance(&p, &register("k1", "")).is_err());
+    assert_eq!(existing_instance(&p, &register("", &id)), Ok(Some(0)));
+    p.services[0].lock().unwrap().ikey = common::secret_digest("k1");
+    assert_eq!(existing_instance(&p, &register("k1", "")), Ok(Some(0)));
+    assert!(existing_instance(&p, &register("k2", "")).is_err());
+    assert!(existing_instance(&p, &register("", "")).is_err(), "registration without a key took over");
+}
+
+// Status refusing a new instance when the namespace holds count instances and
+// its quota allows no more, None when it fits.
+fn over_quota(namespace: &str, count: usize) -> Option<registry::StatusPacket> {
+    let quota = common::instance_quota(namespace);
+    if quota == 0 || count < quota {
+        return None;
+    }
+    tracing::warn!(namespace, quota, "namespace instance quota reached");
+    let msg = format!("namespace {} has reached its quota of {} instances", namespace, quota);
+    Some(common::make_status_packet(common::StatusEnum::EXHAUSTED, msg))
+}
+
+// Status refusing a new entry when the holder already has count of them and
+// the cap set by key in setting.toml allows no more, None when it fits.
+fn over_cap(key: &str, count: usize, holder: String, items: &str) -> Option<registry::StatusPacket> {
+    let cap = limits::cap(key);
+    if cap == 0 || count < cap {
+        return None;
+    }
+    tracing::warn!(key, cap, "{} reached its cap", holder);
+    let msg = format!("{} has reached its cap of {} {}", holder, cap, items);
+    Some(common::make_status_packet(common::StatusEnum::EXHAUSTED, msg))
+}
+
+fn register_error(s: registry::StatusPacket) -> registry::RegisterResponse {
+    registry::RegisterResponse {
+        token: "".to_string(),
+        status: Some(s),
+        schema_version: 0,
+        instance_id: "".to_string(),
+    }
+}
+
 // Remove a protocol registration
 pub fn handle_deregister(req: DeRegisterRequest) -> DeRegisterResponse {
+    let ns = req.namespace.clone();
     let (key, response) = inner_deregister(req);
     if key.len() > 0  {
         let mut protobufs = GDATA.get().unwrap().lock().unwrap();
-        protobufs.protomap.remove(&key);
+        // the protobuf may have been registered again in the meantime
+        if common::release_protobuf_group(&mut protobufs, &ns, &key) {
+            tracing::info!(protobuf_name = %key, "removed protobuf without instances");
+        }
     }
     response
 }
@@ -83,8 +281,7 @@ pub fn handle_deregister(req: DeRegisterRequest) -> DeRegisterResponse {
 // of scope from the mainline processing.
 fn inner_deregister(req: DeRegisterRequest) -> (String, DeRegisterResponse) {
     let token = req.token;
-    let kp = get_keypair();
-    let claim = jwt::validate_token(kp.unwrap(), token);
+    let claim = common::check_scoped_token("unreg", token.clone(), &req.namespace);
     if claim.is_err() {
         let s = make_status_packet(common::StatusEnum::AUTHERROR, claim.unwrap_err());
         let response = DeRegisterResponse {
@@ -95,50 +292,48 @@ fn inner_deregister(req: DeRegisterRequest) -> (String, DeRegisterResponse) {
     let info = claim.unwrap();
     let proto_name = info.subject.unwrap();
     let url = info.custom.user_name;
+    let id = info.custom.instance_id;
     // find the protobuf containing the protobuf group
     let pname = proto_name.clone();
     let protobufs = GDATA.get().unwrap().lock().unwrap();
-    let protocol = find_protobuf(&protobufs, proto_name);
+    let protocol = find_protobuf(&protobufs, &req.namespace, proto_name);
     if protocol.is_none() {
         return unreg_not_found();
     }
-    // search through the service index to find the registration.else
+    // find the registration by the instance id of the token, or by url for
+    // tokens issued before instance ids
     let mut protobuf = protocol.unwrap().lock().unwrap();
     let rsp = DeRegisterResponse {
         status: None,
     };
-    if find_entry_to_remove(&mut protobuf, url) == false {
-        return unreg_not_found();
-    } else {
-        if protobuf.services.is_empty() {
-            return (pname.to_string(), rsp);
-        }
+    let idx = match common::find_instance(&protobuf, &id, &url) {
+        Ok(idx) => idx,
+        Err(_) => return unreg_not_found(),
+    };
+    // a token replaced by a re-registration must not remove the new instance
+    if common::token_replaced(&protobuf.services[idx].lock().unwrap(), &token) {
+        let s = make_status_packet(common::StatusEnum::BADTOKEN, replaced_message());
+        return ("".to_string(), DeRegisterResponse { status: Some(s) });
+    }
+    let svc = protobuf.services.remove(idx).into_inner().unwrap();
+    tracing::info!(protobuf_name = %pname, url = %svc.url, instance_id = %svc.id, "deregistered instance");
+    audit::record(audit::AuditEvent {
+        event: "deregister",
+        namespace: common::namespace_name(&req.namespace),
+        protobuf_name: pname.clone(),
+        url: svc.url.clone(),
+        instance_id: svc.id.clone(),
+        user: url.clone(),
+        ..Default::default()
+    });
+    if protobuf.services.is_empty() {
+        return (pname.to_string(), rsp);
     }
     ("".to_string(), rsp)
 }
 
-// locate the proper service entry to remove.
-fn find_entry_to_remove(protobuf: &mut Protobuf, url: String) -> bool {
-    let ct = protobuf.services.len();
-    if ct < 1 { return false; }
-    let usize = search_services(protobuf, url);
-    if usize == 0 { return false; }
-    let usize = usize -1 ;
-    // found the service entry, get id of it
-    protobuf.services.remove(usize);
-    return true;
-}
-
-fn search_services(protobuf: &mut Protobuf, url: String) -> usize {
-    let ct = protobuf.services.len();
-    for i in 0..ct {
-        let m = &protobuf.services[i];
-        let p = m.lock().unwrap();
-        if p.url == url {
-            return i + 1;
-        }
-    }
-    return 0;
+fn replaced_message() -> String {
+    "registration token was replaced by a newer registration".to_string()
 }
 
 fn unreg_not_found() -> (String, DeRegisterResponse) {
@@ -154,19 +349,38 @@ fn unreg_not_found() -> (String, DeRegisterResponse) {
 pub fn handle_find_provider(req: FindProviderRequest) -> FindProviderResponse {
     let token = req.registry_token;
     let protobuf_name = req.protobuf_name;
-    let kp = get_keypair();
-    let claim = jwt::validate_token(kp.unwrap(), token);
+    let ns = common::namespace_name(&req.namespace);
+    let claim = common::check_scoped_token("find", token, &ns);
     if claim.is_err() {
         let s = make_status_packet(common::StatusEnum::AUTHERROR, claim.unwrap_err());
         let response = FindProviderResponse {
             service_url: "".to_string(),
             status: Some(s),
+            instances: Vec::new(),
         };
         return response;
     }
+    // An optional version requirement limits the candidates.
+    let mut version_req = None;
+    if !req.version_req.is_empty() {
+        match VersionReq::parse(&req.version_req) {
+            Ok(v) => version_req = Some(v),
+            Err(e) => {
+                let msg = format!("invalid version requirement '{}': {}", req.version_req, e);
+                let s = make_status_packet(common::StatusEnum::SERVERROR, msg);
+                let response = FindProviderResponse {
+                    service_url: "".to_string(),
+                    status: Some(s),
+                    instances: Vec::new(),
+                };
+                return response;
+            },
+        }
+    }
+
     // token is good. See if protobuf exists
     let protobufs = GDATA.get().unwrap().lock().unwrap();
-    let r = find_protobuf(&protobufs, protobuf_name);
+    let r = find_protobuf(&protobufs, &ns, protobuf_name);
     if r.is_none() {
         // Doesn't exist
         let s = make_status_packet(common::StatusEnum::NOTFOUND,
@@ -174,59 +388,192 @@ pub fn handle_find_provider(req: FindProviderRequest) -> FindProviderResponse {
         let response = FindProviderResponse {
             service_url: "".to_string(),
             status: Some(s),
+            instances: Vec::new(),
         };
         return response;
     }
-    let protobuf = r.unwrap().lock().unwrap();
+    let mut protobuf = r.unwrap().lock().unwrap();
     if protobuf.services.is_empty() {
         let s = make_status_packet(common::StatusEnum::NOTFOUND,
                                    "protobuf does not exist".to_string());
         let response = FindProviderResponse {
             service_url: "".to_string(),
             status: Some(s),
+            instances: Vec::new(),
         };
         return response;
     }
 
-    // Pick off the start of service list
-    let s = protobuf.services.get(0);
-    let svc = s.unwrap().lock().unwrap();
-    let service_url = &svc.url;
-    // load balancing code goes here
+    // Only serving, uncordoned instances matching the version requirement are
+    // candidates. Draining, not serving and cordoned instances stay registered
+    // but are left out of FIND results.
+    let eligible: Vec<usize> = (0..protobuf.services.len())
+        .filter(|idx| {
+            let svc = protobuf.services[*idx].lock().unwrap();
+            svc.hst == common::HealthEnum::SERVING && !svc.cdn && !protobuf.cdn
+                && version_matches(&svc.ver, &version_req)
+        })
+        .collect();
+    if eligible.is_empty() {
+        let s = make_status_packet(common::StatusEnum::NOTFOUND,
+                                   "no serving instance for protobuf".to_string());
+        let response = FindProviderResponse {
+            service_url: "".to_string(),
+            status: Some(s),
+            instances: Vec::new(),
+        };
+        return response;
+    }
+
+    // Order the eligible services by the requested strategy. A hash key asks
+    // for a sticky instance from the consistent hash ring, otherwise one of
+    // the load based strategies or the plain list order is used.
+    let order = if !req.hash_key.is_empty() {
+        let urls: Vec<String> = eligible.iter()
+            .map(|idx| protobuf.services[*idx].lock().unwrap().url.clone())
+            .collect();
+        protobuf.ring.update(&urls);
+        protobuf.ring.rank(&req.hash_key)
+    } else if req.by_two_choices || req.by_lowest_use {
+        let loads: Vec<i64> = eligible.iter()
+            .map(|idx| {
+                let svc = protobuf.services[*idx].lock().unwrap();
+                balancer::live_load(svc.ctr, svc.hnd)
+            })
+            .collect();
+        if req.by_two_choices {
+            balancer::rank_two_choices(&loads)
+        } else {
+            balancer::rank_by_load(&loads)
+        }
+    } else {
+        (0..eligible.len()).collect()
+    };
+
+    // Return up to max_results candidates. Only the first one counts as
+    // handed out, the rest are failover candidates for the client.
+    let max = req.max_results.max(1) as usize;
+    let mut instances = Vec::new();
+    for (pos, idx) in order.iter().take(max).enumerate() {
+        let mut svc = protobuf.services[eligible[*idx]].lock().unwrap();
+        if pos == 0 {
+            svc.hnd = svc.hnd.saturating_add(1);
+            metrics::find_handout(&ns, &protobuf.name, &svc.url);
+        }
+        instances.push(FindProviderInstance {
+            instance_id: svc.id.clone(),
+            service_url: svc.url.clone(),
+            metadata: svc.meta.clone(),
+            requests: svc.ctr,
+            handed_out: svc.hnd,
+            version: svc.ver.clone(),
+        });
+    }
     let rsp = FindProviderResponse {
-        service_url: service_url.to_string(),
+        service_url: instances[0].service_url.clone(),
         status: None,
+        instances,
     };
     rsp
 }
 
+// An instance matches when there is no requirement, or when it registered a
+// version satisfying it. Unversioned instances never match a requirement.
+fn version_matches(version: &str, req: &Option<VersionReq>) -> bool {
+    match req {
+        None => true,
+        Some(r) => match Version::parse(version) {
+            Ok(v) => r.matches(&v),
+            Err(_) => false,
+        },
+    }
+}
+
+#[test]
+fn test_version_matches() {
+    let req = Some(VersionReq::parse("^2.1").unwrap());
+    assert!(version_matches("2.1.0", &req));
+    assert!(version_matches("2.4.3", &req));
+    assert!(!version_matches("2.0.9", &req));
+    assert!(!version_matches("3.0.0", &req));
+    assert!(!version_matches("", &req), "unversioned instance matched");
+    assert!(version_matches("", &None));
+}
+
+// A keepalive sets the reported health, but cannot lift the not serving state
+// of failed health checks. Only a passing check does that.
+fn keepalive_health(svc: &crate::Service, reported: common::HealthEnum) -> common::HealthEnum {
+    match svc.hcf > 0 && reported == common::HealthEnum::SERVING {
+        true => svc.hst,
+        false => reported,
+    }
+}
+
+#[test]
+fn test_keepalive_health() {
+    let m = common::make_protobuf(&"proto1".to_string());
+    let mut p = m.lock().unwrap();
+    common::add_service(&mut p, "url1".to_string(), None).unwrap();
+    let mut svc = p.services[0].lock().unwrap();
+    assert_eq!(keepalive_health(&svc, common::HealthEnum::DRAINING), common::HealthEnum::DRAINING);
+    svc.hst = common::HealthEnum::NOTSERVING;
+    svc.hcf = 1;
+    assert_eq!(keepalive_health(&svc, common::HealthEnum::SERVING), common::HealthEnum::NOTSERVING,
+               "keepalive lifted a failed health check");
+    assert_eq!(keepalive_health(&svc, common::HealthEnum::DRAINING), common::HealthEnum::DRAINING);
+    svc.hcf = 0;
+    assert_eq!(keepalive_health(&svc, common::HealthEnum::SERVING), common::HealthEnum::SERVING);
+}
+
 // Handle keep alive request
 pub fn handle_keep_alive(req: KeepaliveReport) -> KeepAliveResponse {
+    match check_keep_alive(req) {
+        Ok(update) => {
+            apply_keep_alive(&update);
+            KeepAliveResponse { status: None }
+        },
+        Err(response) => response,
+    }
+}
+
+// Validate a keep alive and find the instance it reports on. Returns the
+// update to apply to the instance. In a cluster the leader collects the
+// updates and replicates them in batches.
+pub fn check_keep_alive(req: KeepaliveReport) -> Result<KeepaliveUpdate, KeepAliveResponse> {
     let token = req.token;
     let count = req.number_requests;
-    let kp = get_keypair();
-    let claim = jwt::validate_token(kp.unwrap(), token);
+    let health = common::HealthEnum::from_i32(req.health);
+    if health.is_none() {
+        let s = make_status_packet(common::StatusEnum::SERVERROR,
+                                   "unknown health state".to_string());
+        let response = KeepAliveResponse {
+            status: Some(s),
+        };
+        return Err(response);
+    }
+    let claim = common::check_scoped_token("alive", token.clone(), &req.namespace);
     if claim.is_err() {
         let s = make_status_packet(common::StatusEnum::AUTHERROR, claim.unwrap_err());
         let response = KeepAliveResponse {
             status: Some(s),
         };
-        return response;
+        return Err(response);
     }
     // token is good
     let c = claim.unwrap();
-    let protobuf_name = c.subject;
+    let protobuf_name = c.subject.unwrap();
     let url = c.custom.user_name;
+    let id = c.custom.instance_id;
     // Now find the service item
     let protobufs = GDATA.get().unwrap().lock().unwrap();
-    let protobuf = find_protobuf(&protobufs, protobuf_name.unwrap());
+    let protobuf = find_protobuf(&protobufs, &req.namespace, protobuf_name.clone());
     if protobuf.is_none() {
         let s = make_status_packet(common::StatusEnum::NOTFOUND,
                                    "protobuf does not exist".to_string());
         let response = KeepAliveResponse {
             status: Some(s),
         };
-        return response;
+        return Err(response);
     }
     // lock the protobuf struct and find the matching service
     let p = protobuf.unwrap().lock().unwrap();
@@ -236,19 +583,53 @@ pub fn handle_keep_alive(req: KeepaliveReport) -> KeepAliveResponse {
         let response = KeepAliveResponse {
             status: Some(s),
         };
-        return response;
+        return Err(response);
     }
 
-    let ct = p.services.len();
-    for i in 0..ct {
-        let m = &p.services[i];
-        let mut x = m.lock().unwrap();
-        if x.url == url {
-            x.ctr = count;
-        }
+    // the instance is found by the id in the token, or by url for tokens
+    // issued before instance ids. An evicted instance has to register again.
+    let idx = match common::find_instance(&p, &id, &url) {
+        Ok(idx) => idx,
+        Err(e) => {
+            let s = make_status_packet(common::StatusEnum::NOTFOUND,
+                                       format!("{}, register again", e));
+            return Err(KeepAliveResponse { status: Some(s) });
+        },
+    };
+    let x = p.services[idx].lock().unwrap();
+    if common::token_replaced(&x, &token) {
+        let s = make_status_packet(common::StatusEnum::BADTOKEN, replaced_message());
+        return Err(KeepAliveResponse { status: Some(s) });
     }
-    let rsp = KeepAliveResponse {
-        status: None,
+    Ok(KeepaliveUpdate {
+        namespace: common::namespace_name(&req.namespace),
+        protobuf_name,
+        instance_id: x.id.clone(),
+        number_requests: count,
+        health: req.health,
+    })
+}
+
+// Apply a keep alive to its instance. An instance removed in the meantime is
+// left alone.
+pub fn apply_keep_alive(update: &KeepaliveUpdate) {
+    let protobufs = GDATA.get().unwrap().lock().unwrap();
+    let Some(m) = find_protobuf(&protobufs, &update.namespace, update.protobuf_name.clone()) else {
+        return;
     };
-    rsp
+    let p = m.lock().unwrap();
+    let Ok(idx) = common::find_instance(&p, &update.instance_id, "") else {
+        return;
+    };
+    let mut x = p.services[idx].lock().unwrap();
+    let now = Instant::now();
+    if let Some(last) = x.lka {
+        metrics::keepalive_lag(now.duration_since(last).as_secs_f64());
+    }
+    x.lka = Some(now);
+    x.lkt = Some(common::now());
+    x.ctr = update.number_requests;
+    x.hnd = 0;
+    let health = common::HealthEnum::from_i32(update.health).unwrap_or(common::HealthEnum::SERVING);
+    x.hst = keepalive_health(&x, health);
 }
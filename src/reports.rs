@@ -14,51 +14,153 @@
  * limitations under the License.
  *
  */
-use crate::common::{get_keypair, make_status_packet};
-use crate::{common, jwt, GDATA};
-use crate::registry::{ByProvider, ByProviderInstance, ProviderReportRequest, ProviderReportResponse};
+use std::collections::HashMap;
+use crate::common::make_status_packet;
+use crate::{common, Service, GDATA};
+use semver::Version;
+use crate::registry::{ByProvider, ByProviderInstance, ByVersion, ProviderReportRequest, ProviderReportResponse};
 
+// The report covers one namespace, the one the request names.
 pub fn handle_provider_report(req: ProviderReportRequest) -> ProviderReportResponse {
     let token = req.token;
-    let kp = get_keypair();
-    let claim = jwt::validate_token(kp.unwrap(), token);
-    if claim.is_err() {
-        let s = make_status_packet(common::StatusEnum::AUTHERROR, claim.unwrap_err());
+    let ns = common::namespace_name(&req.namespace);
+    // A token that does not validate is an AUTHERROR, a valid token used
+    // outside its namespace a BADTOKEN.
+    let checked = common::check_token("report", token)
+        .map_err(|e| make_status_packet(common::StatusEnum::AUTHERROR, e))
+        .and_then(|claim| common::check_token_namespace("report", &claim, &ns)
+            .map_err(|e| make_status_packet(common::StatusEnum::BADTOKEN, e)));
+    if let Err(s) = checked {
         let response = ProviderReportResponse {
-            providers: Vec::new(),
             status: Some(s),
+            ..Default::default()
         };
         return response;
     }
+    let instance_filter = !req.labels.is_empty() || !req.health.is_empty();
 
-    let mut byproviders = Vec::new();
+    let mut byproviders: Vec<ByProvider> = Vec::new();
+    let mut next_page_token = "".to_string();
     let protobufs= GDATA.get().unwrap().lock().unwrap();
+    let empty = HashMap::new();
+    let protomap = protobufs.namespaces.get(&ns).unwrap_or(&empty);
 
-    //let providers = protobufs.protomap.values().cloned().collect::<Vec<Protobuf>>();
-    for provider in protobufs.protomap.values() {
-        let protoitem = provider.lock().unwrap();
+    // Pages follow the protobuf names in sorted order. The page token is the
+    // last name of the previous page.
+    let mut names: Vec<&String> = protomap.keys()
+        .filter(|n| n.starts_with(&req.name_prefix))
+        .filter(|n| req.page_token.is_empty() || n.as_str() > req.page_token.as_str())
+        .collect();
+    names.sort();
+    for name in names {
+        if req.page_size > 0 && byproviders.len() >= req.page_size as usize {
+            // more names follow, the next page starts after the last one returned
+            next_page_token = byproviders.last().unwrap().protobuf_name.clone();
+            break;
+        }
+        let protoitem = protomap[name].lock().unwrap();
         let protoname = protoitem.name.clone();
         let mut byproto = ByProvider {
             protobuf_name: protoname,
             instances: Vec::new(),
+            versions: Vec::new(),
+            cordoned: protoitem.cdn,
         };
         // iterate through services
         for i in 0..protoitem.services.len() {
             let lsrv = &protoitem.services[i];
             let srv = lsrv.lock().unwrap();
-            let url = srv.url.clone();
-            let serv = ByProviderInstance {
-                service_url: url,
-                requests: srv.ctr,
-            };
-            byproto.instances.push(serv);
+            if !instance_matches(&srv, &req.labels, &req.health) {
+                continue;
+            }
+            let mut inst = report_instance(&srv);
+            inst.cordoned |= protoitem.cdn;
+            byproto.instances.push(inst);
+        }
+        // names without a matching instance are left out when filtering instances
+        if instance_filter && byproto.instances.is_empty() {
+            continue;
         }
+        byproto.versions = group_by_version(&byproto.instances);
         byproviders.push(byproto);
     }
 
     let rsp = ProviderReportResponse {
         providers: byproviders,
         status: None,
+        next_page_token,
+        instances: common::instance_count(&protobufs, &ns) as i32,
+        instance_quota: common::instance_quota(&ns) as i32,
+        namespace: ns,
     };
     rsp
 }
+
+fn report_instance(srv: &Service) -> ByProviderInstance {
+    ByProviderInstance {
+        instance_id: srv.id.clone(),
+        service_url: srv.url.clone(),
+        requests: srv.ctr,
+        last_report: srv.lkt.map(|t| t.into()),
+        handed_out: srv.hnd,
+        health: srv.hst as i32,
+        version: srv.ver.clone(),
+        registered: Some(srv.rgt.into()),
+        metadata: srv.meta.clone(),
+        token_expires: srv.tke.map(|t| t.into()),
+        cordoned: srv.cdn,
+    }
+}
+
+// An instance matches when it carries every requested label and, if health
+// states are given, is in one of them.
+fn instance_matches(srv: &Service, labels: &HashMap<String, String>, health: &[i32]) -> bool {
+    let labeled = labels.iter().all(|(k, v)| srv.meta.get(k) == Some(v));
+    labeled && (health.is_empty() || health.contains(&(srv.hst as i32)))
+}
+
+// Group instances by version, newest version first. Unversioned instances
+// and versions that are not valid semver sort last.
+fn group_by_version(instances: &[ByProviderInstance]) -> Vec<ByVersion> {
+    let mut groups: Vec<ByVersion> = Vec::new();
+    for inst in instances {
+        match groups.iter_mut().find(|g| g.version == inst.version) {
+            Some(g) => g.instances.push(inst.clone()),
+            None => groups.push(ByVersion {
+                version: inst.version.clone(),
+                instances: vec![inst.clone()],
+            }),
+        }
+    }
+    groups.sort_by_key(|g| std::cmp::Reverse(Version::parse(&g.version).ok()));
+    groups
+}
+
+#[test]
+fn test_group_by_version() {
+    let inst = |url: &str, version: &str| ByProviderInstance {
+        service_url: url.to_string(),
+        version: version.to_string(),
+        ..Default::default()
+    };
+    let instances = vec![inst("a", "1.2.0"), inst("b", ""), inst("c", "2.0.0"), inst("d", "1.2.0")];
+    let groups = group_by_version(&instances);
+    let versions: Vec<&str> = groups.iter().map(|g| g.version.as_str()).collect();
+    assert_eq!(versions, vec!["2.0.0", "1.2.0", ""]);
+    assert_eq!(groups[1].instances.len(), 2);
+}
+
+#[test]
+fn test_instance_matches() {
+    let m = common::make_service("url1".to_string(), None);
+    let mut srv = m.lock().unwrap();
+    srv.meta.insert("zone".to_string(), "east".to_string());
+    srv.hst = common::HealthEnum::DRAINING;
+    let labels = |k: &str, v: &str| HashMap::from([(k.to_string(), v.to_string())]);
+    assert!(instance_matches(&srv, &HashMap::new(), &[]));
+    assert!(instance_matches(&srv, &labels("zone", "east"), &[]));
+    assert!(!instance_matches(&srv, &labels("zone", "west"), &[]));
+    assert!(!instance_matches(&srv, &labels("tier", "east"), &[]));
+    assert!(instance_matches(&srv, &HashMap::new(), &[1, 2]));
+    assert!(!instance_matches(&srv, &HashMap::new(), &[0]));
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Schema registry. Providers may send the serialized FileDescriptorSet of
+// their API when they register. Each distinct descriptor set is kept as a new
+// version of the protobuf name so clients can fetch the contract behind it.
+
+use prost::Message;
+use prost_types::FileDescriptorSet;
+use crate::common::{find_protobuf, make_status_packet};
+use crate::registry::{SchemaRequest, SchemaResponse};
+use crate::{common, Protobuf, GDATA};
+
+// Decode a serialized descriptor set, rejecting anything that is not one.
+pub fn decode_schema(data: &[u8]) -> Result<FileDescriptorSet, String> {
+    match FileDescriptorSet::decode(data) {
+        Ok(fds) => {
+            if fds.file.is_empty() {
+                return Err("file descriptor set has no files".to_string());
+            }
+            Ok(fds)
+        },
+        Err(e) => Err(format!("invalid file descriptor set: {}", e)),
+    }
+}
+
+// Store the descriptor set as the next schema version of the protobuf.
+// Sending the same descriptor set as the latest version does not create a
+// new version, so every instance of a build shares one version number.
+// The protobuf must already be locked.
+pub fn store_schema(protobuf: &mut Protobuf, data: &[u8]) -> i32 {
+    if protobuf.schemas.last().map(|v| v.as_slice()) == Some(data) {
+        return protobuf.schemas.len() as i32;
+    }
+    protobuf.schemas.push(data.to_vec());
+    protobuf.schemas.len() as i32
+}
+
+// Fetch a stored schema. Version 0 asks for the latest one.
+pub fn handle_schema(req: SchemaRequest) -> SchemaResponse {
+    if let Err(e) = common::check_scoped_token("schema", req.token, &req.namespace) {
+        let s = make_status_packet(common::StatusEnum::AUTHERROR, e);
+        return schema_error(req.protobuf_name, s);
+    }
+
+    let protobufs = GDATA.get().unwrap().lock().unwrap();
+    let protobuf = match find_protobuf(&protobufs, &req.namespace, req.protobuf_name.clone()) {
+        Some(p) => p.lock().unwrap(),
+        None => {
+            let s = make_status_packet(common::StatusEnum::NOTFOUND,
+                                       "protobuf does not exist".to_string());
+            return schema_error(req.protobuf_name, s);
+        },
+    };
+    let ct = protobuf.schemas.len() as i32;
+    let version = if req.version == 0 { ct } else { req.version };
+    if version < 1 || version > ct {
+        let s = make_status_packet(common::StatusEnum::NOTFOUND,
+                                   "schema version not found".to_string());
+        return schema_error(req.protobuf_name, s);
+    }
+    SchemaResponse {
+        protobuf_name: req.protobuf_name,
+        version,
+        file_descriptor_set: protobuf.schemas[(version - 1) as usize].clone(),
+        versions: (1..=ct).collect(),
+        status: None,
+    }
+}
+
+fn schema_error(protobuf_name: String, status: crate::registry::StatusPacket) -> SchemaResponse {
+    SchemaResponse {
+        protobuf_name,
+        version: 0,
+        file_descriptor_set: Vec::new(),
+        versions: Vec::new(),
+        status: Some(status),
+    }
+}
+
+#[test]
+fn test_store_schema_versions() {
+    use prost_types::FileDescriptorProto;
+
+    let make = |name: &str| {
+        let fds = FileDescriptorSet {
+            file: vec![FileDescriptorProto {
+                name: Some(name.to_string()),
+                package: Some("test".to_string()),
+                ..Default::default()
+            }],
+        };
+        fds.encode_to_vec()
+    };
+    let mut protobuf = Protobuf {
+        name: "proto1".to_string(),
+        cltk: None,
+        services: Vec::new(),
+        schemas: Vec::new(),
+        compat: None,
+        cdn: false,
+        ring: Default::default(),
+    };
+    let v1 = make("a.proto");
+    let v2 = make("b.proto");
+    assert!(decode_schema(&v1).is_ok());
+    assert!(decode_schema(&[0xff, 0xff, 0xff]).is_err(), "garbage accepted");
+    assert!(decode_schema(&[]).is_err(), "empty set accepted");
+    assert_eq!(store_schema(&mut protobuf, &v1), 1);
+    assert_eq!(store_schema(&mut protobuf, &v1), 1, "same schema made a new version");
+    assert_eq!(store_schema(&mut protobuf, &v2), 2);
+    assert_eq!(protobuf.schemas.len(), 2);
+}
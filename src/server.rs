@@ -17,54 +17,101 @@
 
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 //ToDo add load balancer support
-//ToDo add timestamps to some protobuf messages
 //ToDo convert protobufs/protobuf to mongodb
 ///////////////////////////////////////////////////////////////////////////////////////////////////
 
 use tonic::{transport::Server, Request, Response, Status};
+use tonic_health::ServingStatus;
+use tracing::{error, info, warn};
 
 pub mod jwt;
 pub mod common;
 pub mod reports;
 pub mod authorize;
 pub mod registrations;
+pub mod balancer;
+pub mod checker;
+pub mod state;
+pub mod schemas;
+pub mod compat;
+pub mod metrics;
+pub mod logging;
+pub mod propagation;
+pub mod telemetry;
+pub mod audit;
+pub mod export;
+pub mod webadmin;
+pub mod admin;
+pub mod limits;
+pub mod cluster;
+pub mod gossip;
 
 use crate::registry::registry_server::{Registry, RegistryServer};
 
 // Import the generated proto-rust file into a module
 pub mod registry {
     tonic::include_proto!("registry");
+
+    // Encoded descriptors of the registry package for server reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("registry_descriptor");
 }
 
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Instant, SystemTime};
 
 // Specific protobuf instance for a named group
 #[derive(Debug)]
 pub struct Service {
-    pub url: String,    // gRPC service URL (host:port)
-    pub stk: Option<String>,    // Server token of registree
+    pub id: String,     // server assigned instance id, kept by re-registrations
+    pub url: String,    // gRPC service URL (host:port), may be shared
+    pub stk: Option<String>,    // digest of the server token of registree
     pub ctr: i32,       // number of keepalives
+    pub hnd: i32,       // FIND hand-outs since last keepalive, saturating
+    pub meta: HashMap<String, String>,  // labels supplied at registration
+    pub hst: common::HealthEnum,    // health reported by keepalive
+    pub hcf: i32,       // consecutive failed health checks
+    pub sver: i32,      // schema version registered with, 0 for none
+    pub ver: String,    // semver API version, empty when unversioned
+    pub lka: Option<Instant>,   // time of the last keepalive
+    pub rgt: SystemTime,        // registration time
+    pub lkt: Option<SystemTime>,    // wall clock time of the last keepalive
+    pub tke: Option<SystemTime>,    // expiry of the registration token
+    pub cdn: bool,      // cordoned by an admin, left out of FIND
+    pub ikey: String,   // digest of the instance key of the registering process, may be empty
 }
 
 // Specific protobuf group basis
 #[derive(Debug)]
 pub struct Protobuf {
     pub name: String,   // protobuf name
-    pub cltk: Option<String>,   // Client token for authorize
+    pub cltk: Option<String>,   // digest of the client token for authorize
     pub services: Vec<Mutex<Service>>,
+    pub schemas: Vec<Vec<u8>>,  // FileDescriptorSet per schema version
+    pub compat: Option<compat::CompatEnum>, // schema compatibility, None for default
+    pub cdn: bool,      // every instance cordoned by an admin
+    pub ring: balancer::Ring,   // hash ring of the instances FIND last chose from
 }
 
 // General root for all protobuf grouping. It also provides data
-// used throughout the registry application.
+// used throughout the registry application. Protobuf groups are kept per
+// namespace so separate teams or environments can use the same names.
 #[derive(Debug)]
 pub struct Protobufs {
-    pub protomap: HashMap<String, Mutex<Protobuf>>,
+    pub namespaces: HashMap<String, HashMap<String, Mutex<Protobuf>>>,
 }
 
 use once_cell::sync::OnceCell;
 static GDATA: OnceCell<Mutex<Protobufs>> = OnceCell::new();
 static KPAIR: OnceCell<RS256KeyPair> = OnceCell::new();
+static SETTINGS: OnceCell<HashMap<String, String>> = OnceCell::new();
+// Set once shutdown starts so new registrations are turned away.
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+// Set once the state is loaded and replication is set up.
+static READY: AtomicBool = AtomicBool::new(false);
 
 // Implement the service skeleton for the "registry" service
 // defined in the proto
@@ -80,70 +127,304 @@ impl Registry for MyRegistry {
     async fn auth(
         &self, request: Request<registry::AuthorizeRequest>, )
         -> Result<Response<registry::AuthorizeResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("auth", start, peer)?;
+        leader("auth", start)?;
+        let span = logging::rpc_span("auth", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
         let req = request.into_inner();
-        let response = authorize::handle_authorize(req.protobuf_name);
+        let response = write("auth", start, span, peer, req,
+                             |req| authorize::handle_authorize(&req.namespace, req.protobuf_name)).await?;
+        metrics::observe_rpc("auth", start, &response.status);
         Ok(Response::new(response))
     }
 
     async fn regs(
         &self, request: Request<registry::RegisterRequest>, )
         -> Result<Response<registry::RegisterResponse>, Status> {
+        let start = Instant::now();
+        if SHUTDOWN.load(Ordering::SeqCst) {
+            metrics::observe_rpc_code("regs", start, "UNAVAILABLE");
+            return Err(Status::unavailable("registry is shutting down"));
+        }
+        let peer = request.remote_addr();
+        limit("regs", start, peer)?;
+        leader("regs", start)?;
+        let span = logging::rpc_span("regs", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
         let req = request.into_inner();
-        let response = registrations::handle_register(&req);
+        let response = write("regs", start, span, peer, req, |req| registrations::handle_register(&req)).await?;
+        if let Some(status) = exhausted("regs", start, &response.status) {
+            return Err(status);
+        }
+        metrics::observe_rpc("regs", start, &response.status);
         Ok(Response::new(response))
     }
 
     async fn unreg(
         &self, request: Request<registry::DeRegisterRequest>, )
         -> Result<Response<registry::DeRegisterResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("unreg", start, peer)?;
+        leader("unreg", start)?;
+        let span = logging::rpc_span("unreg", &request.get_ref().namespace, "", request.metadata());
         let req = request.into_inner();
-        let response = registrations::handle_deregister(req);
+        let response = write("unreg", start, span, peer, req, registrations::handle_deregister).await?;
+        metrics::observe_rpc("unreg", start, &response.status);
         Ok(Response::new(response))
     }
 
     async fn find(
         &self, request: Request<registry::FindProviderRequest>, )
         -> Result<Response<registry::FindProviderResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("find", start, peer)?;
+        let span = logging::rpc_span("find", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
         let req = request.into_inner();
-        let response = registrations::handle_find_provider(req);
+        let response = in_rpc("find", start, span, peer, || registrations::handle_find_provider(req))?;
+        metrics::observe_rpc("find", start, &response.status);
         Ok(Response::new(response))
     }
 
     async fn alive(
         &self, request: Request<registry::KeepaliveReport>, )
         -> Result<Response<registry::KeepAliveResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("alive", start, peer)?;
+        leader("alive", start)?;
+        let span = logging::rpc_span("alive", &request.get_ref().namespace, "", request.metadata());
+        let req = request.into_inner();
+        let response = match cluster::enabled() {
+            // the leader batches keepalives into the cluster log
+            true => in_rpc("alive", start, span, peer, || registrations::check_keep_alive(req))?
+                .map_or_else(|response| response, |update| {
+                    cluster::keep_alive(update);
+                    registry::KeepAliveResponse { status: None }
+                }),
+            false => in_rpc("alive", start, span, peer, || registrations::handle_keep_alive(req))?,
+        };
+        metrics::observe_rpc("alive", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn schema(
+        &self, request: Request<registry::SchemaRequest>, )
+        -> Result<Response<registry::SchemaResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("schema", start, peer)?;
+        let span = logging::rpc_span("schema", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
         let req = request.into_inner();
-        let response = registrations::handle_keep_alive(req);
+        let response = in_rpc("schema", start, span, peer, || schemas::handle_schema(req))?;
+        metrics::observe_rpc("schema", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn compat(
+        &self, request: Request<registry::CompatRequest>, )
+        -> Result<Response<registry::CompatResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("compat", start, peer)?;
+        leader("compat", start)?;
+        let span = logging::rpc_span("compat", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
+        let req = request.into_inner();
+        let response = write("compat", start, span, peer, req, compat::handle_compat).await?;
+        metrics::observe_rpc("compat", start, &response.status);
         Ok(Response::new(response))
     }
 
     async fn report(
         &self, request: Request<registry::ProviderReportRequest>, )
         -> Result<Response<registry::ProviderReportResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("report", start, peer)?;
+        let span = logging::rpc_span("report", &request.get_ref().namespace, "", request.metadata());
+        let req = request.into_inner();
+        let response = in_rpc("report", start, span, peer, || reports::handle_provider_report(req))?;
+        metrics::observe_rpc("report", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn adminauth(
+        &self, request: Request<registry::AdminAuthRequest>, )
+        -> Result<Response<registry::AuthorizeResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("adminauth", start, peer)?;
+        let span = logging::rpc_span("adminauth", &request.get_ref().namespace, "", request.metadata());
+        let req = request.into_inner();
+        let response = in_rpc("adminauth", start, span, peer, || admin::handle_admin_auth(req))?;
+        metrics::observe_rpc("adminauth", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn evict(
+        &self, request: Request<registry::RemoveInstanceRequest>, )
+        -> Result<Response<registry::AdminResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("evict", start, peer)?;
+        leader("evict", start)?;
+        let span = logging::rpc_span("evict", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
+        let req = request.into_inner();
+        let response = write("evict", start, span, peer, req, admin::handle_remove_instance).await?;
+        metrics::observe_rpc("evict", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn purge(
+        &self, request: Request<registry::RemoveProtobufRequest>, )
+        -> Result<Response<registry::AdminResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("purge", start, peer)?;
+        leader("purge", start)?;
+        let span = logging::rpc_span("purge", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
+        let req = request.into_inner();
+        let response = write("purge", start, span, peer, req, admin::handle_remove_protobuf).await?;
+        metrics::observe_rpc("purge", start, &response.status);
+        Ok(Response::new(response))
+    }
+
+    async fn revoke(
+        &self, request: Request<registry::ClearClientTokenRequest>, )
+        -> Result<Response<registry::AdminResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("revoke", start, peer)?;
+        leader("revoke", start)?;
+        let span = logging::rpc_span("revoke", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
         let req = request.into_inner();
-        let response = reports::handle_provider_report(req);
+        let response = write("revoke", start, span, peer, req, admin::handle_clear_client_token).await?;
+        metrics::observe_rpc("revoke", start, &response.status);
         Ok(Response::new(response))
     }
+
+    async fn cordon(
+        &self, request: Request<registry::CordonRequest>, )
+        -> Result<Response<registry::AdminResponse>, Status> {
+        let start = Instant::now();
+        let peer = request.remote_addr();
+        limit("cordon", start, peer)?;
+        leader("cordon", start)?;
+        let span = logging::rpc_span("cordon", &request.get_ref().namespace, &request.get_ref().protobuf_name,
+                                     request.metadata());
+        let req = request.into_inner();
+        let response = write("cordon", start, span, peer, req, admin::handle_cordon).await?;
+        metrics::observe_rpc("cordon", start, &response.status);
+        Ok(Response::new(response))
+    }
+}
+
+// Apply the rate limits to a call before its handler runs.
+#[allow(clippy::result_large_err)]
+fn limit(method: &str, start: Instant, peer: Option<SocketAddr>) -> Result<(), Status> {
+    limits::check(method, peer)
+        .inspect_err(|_| metrics::observe_rpc_code(method, start, "RESOURCE_EXHAUSTED"))
+}
+
+// Quota and cap rejections of a handler go out as RESOURCE_EXHAUSTED.
+fn exhausted(method: &str, start: Instant, status: &Option<registry::StatusPacket>) -> Option<Status> {
+    match status {
+        Some(s) if s.code == common::StatusEnum::EXHAUSTED as i32 => {
+            metrics::observe_rpc_code(method, start, "RESOURCE_EXHAUSTED");
+            Some(Status::resource_exhausted(s.error_message.clone()))
+        },
+        _ => None,
+    }
+}
+
+// In a cluster only the leader takes writes.
+#[allow(clippy::result_large_err)]
+fn leader(method: &str, start: Instant) -> Result<(), Status> {
+    cluster::check_leader()
+        .inspect_err(|_| metrics::observe_rpc_code(method, start, "UNAVAILABLE"))
+}
+
+// Run a write. In a cluster it goes through the cluster log, every node runs
+// the handler once a majority holds the write and the caller gets the
+// response of the leader's run.
+#[allow(clippy::result_large_err)]
+async fn write<Q, R>(method: &str, start: Instant, span: tracing::Span, peer: Option<SocketAddr>, req: Q,
+                     handler: impl FnOnce(Q) -> R) -> Result<R, Status>
+    where Q: prost::Message, R: prost::Message + Default {
+    if !cluster::enabled() {
+        return in_rpc(method, start, span, peer, || handler(req));
+    }
+    let response = cluster::write(method, req.encode_to_vec(), peer).await
+        .inspect_err(|_| metrics::observe_rpc_code(method, start, "UNAVAILABLE"))?;
+    R::decode(response.as_slice()).map_err(|e| Status::internal(format!("invalid cluster response: {}", e)))
+}
+
+// Registry calls are refused with UNAVAILABLE until the registry is ready.
+#[allow(clippy::result_large_err)]
+fn check_ready(request: Request<()>) -> Result<Request<()>, Status> {
+    match READY.load(Ordering::SeqCst) {
+        true => Ok(request),
+        false => Err(Status::unavailable("registry is starting")),
+    }
+}
+
+// Run a handler inside its RPC span with the caller's address available to
+// the audit log. A token over the rate limit of its subject fails the call
+// with RESOURCE_EXHAUSTED.
+#[allow(clippy::result_large_err)]
+fn in_rpc<T>(method: &str, start: Instant, span: tracing::Span, peer: Option<SocketAddr>,
+             f: impl FnOnce() -> T) -> Result<T, Status> {
+    span.in_scope(|| audit::with_peer(peer, || limits::subject_limited(f)))
+        .map_err(|e| {
+            metrics::observe_rpc_code(method, start, "RESOURCE_EXHAUSTED");
+            Status::resource_exhausted(e)
+        })
 }
 
 // Runtime to run our server
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
+    // Standard grpc.health.v1 service. Both the overall server and the
+    // registry service report NOT_SERVING until the registry state is loaded.
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    health.set_not_serving::<RegistryServer<MyRegistry>>().await;
+    health.set_service_status("", ServingStatus::NotServing).await;
+
     let params = getconfig();
+    let tracer = telemetry::init_tracer(&params);
+    logging::init(&params, tracer.as_ref().ok().and_then(|t| t.as_ref()));
+    let tracer = match tracer {
+        Ok(t) => t,
+        Err(e) => {
+            error!(error = %e, "span export disabled");
+            None
+        },
+    };
+    let _ = SETTINGS.set(params.clone());
+    limits::init(&params);
+    if let Err(e) = audit::init(&params) {
+        error!(error = %e, "failed to open audit log");
+        std::process::exit(95);
+    }
     let keyfile = params.get("public_key_file").unwrap().clone();
     let server_address = params.get("server_address").unwrap();
     let keyname = keyfile.clone();
     match jwt::load_pem(keyfile) {
         Ok(kp) => {
-            let ps = Protobufs {
-                protomap: HashMap::new(),
-            };
             let _ = KPAIR.set(kp);
-            let _ = GDATA.set(Mutex::new(ps));
         },
         Err(e) => {
-            println!("Failed to load key pair from {} {}", keyname, e);
+            error!(keyfile = %keyname, error = %e, "failed to load key pair");
             std::process::exit(97);
         },
     }
@@ -151,15 +432,136 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let addr = server_address.parse()?;
     let serv = MyRegistry::default();
 
-    println!("Starting gRPC Registration server on {}", server_address);
-    Server::builder()
-        .add_service(RegistryServer::new(serv))
-        .serve(addr)
-        .await?;
+    // Server reflection so tools like grpcurl can list and describe the
+    // registry and health services.
+    let reflection = tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(registry::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build_v1()?;
+
+    // The server starts right away so health checks see NOT_SERVING while the
+    // state loads. Registry calls are refused until then.
+    info!(address = %server_address, "starting gRPC registration server");
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let mut server = tokio::spawn(Server::builder()
+        .add_service(health_service)
+        .add_service(reflection)
+        .add_service(RegistryServer::with_interceptor(serv, check_ready))
+        .serve_with_shutdown(addr, async { let _ = stopped.await; }));
+
+    // Registry state is restored from the state file when one is configured.
+    let state_file = params.get("state_file").cloned();
+    let ps = match &state_file {
+        Some(file) => match state::load_state(file) {
+            Ok(ps) => ps,
+            Err(e) => {
+                error!(error = %e, "failed to load registry state");
+                std::process::exit(96);
+            },
+        },
+        None => Protobufs {
+            namespaces: HashMap::new(),
+        },
+    };
+    let _ = GDATA.set(Mutex::new(ps));
+
+    // Optional replication, by gossip between nodes that all take writes or
+    // by a cluster with an elected leader.
+    if let Err(e) = gossip::init(&params) {
+        error!(error = %e, "failed to join gossip mesh");
+        std::process::exit(94);
+    }
+    if let Err(e) = cluster::init(&params) {
+        error!(error = %e, "failed to join registry cluster");
+        std::process::exit(94);
+    }
+
+    // Optional active health checking of the registered services.
+    let interval = config_number(&params, "health_check_interval", 0);
+    if interval > 0 {
+        let timeout = config_number(&params, "health_check_timeout", 2);
+        // at least one failed check before an eviction
+        let failures = config_number(&params, "health_check_failures", 3).max(1);
+        info!(interval, "health checking registered services");
+        tokio::spawn(checker::run_checker(std::time::Duration::from_secs(interval),
+                                          std::time::Duration::from_secs(timeout),
+                                          i32::try_from(failures).unwrap_or(i32::MAX)));
+    }
+
+    // Optional prometheus metrics listener.
+    if let Some(metrics_address) = params.get("metrics_address") {
+        info!(address = %metrics_address, "serving metrics");
+        tokio::spawn(metrics::serve(metrics_address.clone()));
+    }
+
+    // Optional HTTP admin endpoint serving the report in export formats.
+    if let Some(admin_address) = params.get("admin_address") {
+        info!(address = %admin_address, "serving admin endpoint");
+        tokio::spawn(webadmin::serve(admin_address.clone()));
+    }
+
+    // Everything is set up, take registry calls and report serving.
+    READY.store(true, Ordering::SeqCst);
+    health.set_serving::<RegistryServer<MyRegistry>>().await;
+    health.set_service_status("", ServingStatus::Serving).await;
+    info!("registry ready");
 
+    tokio::select! {
+        result = &mut server => {
+            // The server stopped on its own, which only happens on error.
+            result??;
+        },
+        _ = shutdown_signal() => {
+            // Report not serving and refuse registrations first, then stop
+            // accepting connections and give in-flight calls time to finish.
+            info!("shutting down gRPC registration server");
+            SHUTDOWN.store(true, Ordering::SeqCst);
+            health.set_not_serving::<RegistryServer<MyRegistry>>().await;
+            health.set_service_status("", ServingStatus::NotServing).await;
+            let _ = stop.send(());
+            let deadline = config_number(&params, "shutdown_timeout", 10);
+            let drained = tokio::time::timeout(
+                std::time::Duration::from_secs(deadline), &mut server).await;
+            if drained.is_err() {
+                warn!(deadline, "in-flight requests did not finish in time");
+                server.abort();
+            }
+        },
+    }
+
+    // Flush the registry state so it survives the restart.
+    if let Some(file) = state_file {
+        let protobufs = GDATA.get().unwrap().lock().unwrap();
+        match state::save_state(&protobufs, &file) {
+            Ok(_) => info!(file = %file, "registry state saved"),
+            Err(e) => error!(error = %e, "failed to save registry state"),
+        }
+    }
+    telemetry::shutdown(tracer);
     Ok(())
 }
 
+// Completes on SIGINT (ctrl-c) or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    let interrupt = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => { sig.recv().await; },
+            Err(_) => std::future::pending::<()>().await,
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {},
+        _ = terminate => {},
+    }
+}
+
 // Load configuration parameters
 use config::{Config};
 use jwt_simple::algorithms::RS256KeyPair;
@@ -181,3 +583,11 @@ pub fn getconfig() -> HashMap<String, String> {
     themap
 }
 
+// Numeric configuration value with a default when it is missing or invalid.
+pub fn config_number(params: &HashMap<String, String>, key: &str, default: u64) -> u64 {
+    match params.get(key) {
+        Some(v) => v.parse::<u64>().unwrap_or(default),
+        None => default,
+    }
+}
+
@@ -0,0 +1,278 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// Persistent registry state. The protobuf groups of every namespace, their
+// services and stored schemas are written to a JSON file on shutdown and read
+// back at startup so providers keep their registrations and tokens across a
+// restart. Tokens and instance keys are kept as digests. Counters that only
+// make sense while running (FIND hand-outs, failed health checks, keepalive
+// times) are not kept.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::common::HealthEnum;
+use crate::compat::CompatEnum;
+use crate::{Protobuf, Protobufs, Service};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServiceState {
+    #[serde(default)]
+    pub id: String,     // new id for older state files
+    pub url: String,
+    pub stk: Option<String>,
+    pub ctr: i32,
+    pub meta: HashMap<String, String>,
+    pub hst: i32,
+    #[serde(default)]
+    pub sver: i32,
+    #[serde(default)]
+    pub ver: String,
+    #[serde(default)]
+    pub rgt: Option<u64>,   // unix seconds, load time for older state files
+    #[serde(default)]
+    pub lkt: Option<u64>,
+    #[serde(default)]
+    pub tke: Option<u64>,
+    #[serde(default)]
+    pub cdn: bool,
+    #[serde(default)]
+    pub ikey: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProtobufState {
+    #[serde(default)]
+    pub namespace: String,  // default namespace for older state files
+    pub name: String,
+    pub cltk: Option<String>,
+    pub services: Vec<ServiceState>,
+    #[serde(default)]
+    pub schemas: Vec<Vec<u8>>,
+    #[serde(default)]
+    pub compat: Option<String>,
+    #[serde(default)]
+    pub cdn: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RegistryState {
+    pub protobufs: Vec<ProtobufState>,
+    #[serde(default)]
+    pub digests: bool,  // false for older state files holding the tokens themselves
+}
+
+// Copy the registry into the serializable form. The protobufs structure
+// must already be locked.
+pub fn snapshot(protobufs: &Protobufs) -> RegistryState {
+    let mut state = RegistryState {
+        protobufs: Vec::new(),
+        digests: true,
+    };
+    for (ns, m) in protobufs.namespaces.iter()
+        .flat_map(|(ns, protomap)| protomap.values().map(move |m| (ns, m))) {
+        let protobuf = m.lock().unwrap();
+        let mut pstate = protobuf_state(ns, &protobuf);
+        for s in protobuf.services.iter() {
+            pstate.services.push(service_state(&s.lock().unwrap()));
+        }
+        state.protobufs.push(pstate);
+    }
+    state
+}
+
+// Serializable form of a protobuf group without its services.
+pub fn protobuf_state(namespace: &str, protobuf: &Protobuf) -> ProtobufState {
+    ProtobufState {
+        namespace: namespace.to_string(),
+        name: protobuf.name.clone(),
+        cltk: protobuf.cltk.clone(),
+        services: Vec::new(),
+        schemas: protobuf.schemas.clone(),
+        compat: protobuf.compat.map(|m| m.name().to_string()),
+        cdn: protobuf.cdn,
+    }
+}
+
+pub fn service_state(svc: &Service) -> ServiceState {
+    ServiceState {
+        id: svc.id.clone(),
+        url: svc.url.clone(),
+        stk: svc.stk.clone(),
+        ctr: svc.ctr,
+        meta: svc.meta.clone(),
+        hst: svc.hst as i32,
+        sver: svc.sver,
+        ver: svc.ver.clone(),
+        rgt: Some(unix_secs(svc.rgt)),
+        lkt: svc.lkt.map(unix_secs),
+        tke: svc.tke.map(unix_secs),
+        cdn: svc.cdn,
+        ikey: svc.ikey.clone(),
+    }
+}
+
+// Rebuild a service. Counters that are not kept start from zero.
+pub fn restore_service(sstate: ServiceState) -> Service {
+    let id = match sstate.id.is_empty() {
+        true => crate::common::new_instance_id(),
+        false => sstate.id,
+    };
+    Service {
+        id,
+        url: sstate.url,
+        stk: sstate.stk,
+        ctr: sstate.ctr,
+        hnd: 0,
+        meta: sstate.meta,
+        hst: HealthEnum::from_i32(sstate.hst).unwrap_or(HealthEnum::SERVING),
+        hcf: 0,
+        sver: sstate.sver,
+        ver: sstate.ver,
+        lka: None,
+        rgt: sstate.rgt.map(from_unix_secs).unwrap_or_else(SystemTime::now),
+        lkt: sstate.lkt.map(from_unix_secs),
+        tke: sstate.tke.map(from_unix_secs),
+        cdn: sstate.cdn,
+        ikey: sstate.ikey,
+    }
+}
+
+fn unix_secs(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn from_unix_secs(secs: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+// Rebuild the registry from its serialized form.
+pub fn restore(state: RegistryState) -> Protobufs {
+    let mut protobufs = Protobufs {
+        namespaces: HashMap::new(),
+    };
+    let digest = |secret: Option<String>| match state.digests {
+        true => secret,
+        false => secret.as_deref().map(crate::common::secret_digest),
+    };
+    for mut pstate in state.protobufs {
+        pstate.cltk = digest(pstate.cltk);
+        let mut services = Vec::new();
+        for mut sstate in pstate.services {
+            sstate.stk = digest(sstate.stk);
+            sstate.ikey = digest(Some(sstate.ikey)).unwrap_or_default();
+            services.push(Mutex::new(restore_service(sstate)));
+        }
+        let protobuf = Protobuf {
+            name: pstate.name.clone(),
+            cltk: pstate.cltk,
+            services,
+            schemas: pstate.schemas,
+            compat: pstate.compat.and_then(|m| CompatEnum::from_name(&m)),
+            cdn: pstate.cdn,
+            ring: Default::default(),
+        };
+        protobufs.namespaces.entry(crate::common::namespace_name(&pstate.namespace)).or_default()
+            .insert(pstate.name, Mutex::new(protobuf));
+    }
+    protobufs
+}
+
+// Write the registry state to the file. The data goes to a temporary file
+// first and is renamed over the old state so a crash never leaves a partial file.
+pub fn save_state(protobufs: &Protobufs, file: &str) -> Result<(), String> {
+    let state = snapshot(protobufs);
+    let data = match serde_json::to_string_pretty(&state) {
+        Ok(v) => v,
+        Err(e) => return Err(format!("failed to serialize registry state: {}", e)),
+    };
+    let tmpfile = format!("{}.tmp", file);
+    if let Err(e) = fs::write(&tmpfile, data) {
+        return Err(format!("failed to write to file '{}': {}", tmpfile, e));
+    }
+    if let Err(e) = fs::rename(&tmpfile, file) {
+        return Err(format!("failed to rename '{}' to '{}': {}", tmpfile, file, e));
+    }
+    Ok(())
+}
+
+// Read the registry state from the file. A missing file is a fresh start.
+pub fn load_state(file: &str) -> Result<Protobufs, String> {
+    let data = match fs::read_to_string(file) {
+        Ok(v) => v,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(restore(RegistryState { protobufs: Vec::new(), digests: true }));
+        },
+        Err(e) => return Err(format!("failed to read from file '{}': {}", file, e)),
+    };
+    match serde_json::from_str::<RegistryState>(&data) {
+        Ok(state) => Ok(restore(state)),
+        Err(e) => Err(format!("invalid registry state in '{}': {}", file, e)),
+    }
+}
+
+#[test]
+fn test_save_and_load_state() {
+    use crate::common::{add_protobuf, add_service, find_protobuf};
+    let mut protobufs = Protobufs {
+        namespaces: HashMap::new(),
+    };
+    add_protobuf(&mut protobufs, "", "proto1".to_string()).unwrap();
+    add_protobuf(&mut protobufs, "dev", "proto1".to_string()).unwrap();
+    let (id, registered) = {
+        let mut p = find_protobuf(&protobufs, "", "proto1".to_string()).unwrap().lock().unwrap();
+        add_service(&mut p, "url1".to_string(), Some("tok".to_string())).unwrap();
+        let mut svc = p.services[0].lock().unwrap();
+        svc.ctr = 7;
+        svc.hnd = 3;
+        svc.hst = HealthEnum::DRAINING;
+        svc.cdn = true;
+        (svc.id.clone(), unix_secs(svc.rgt))
+    };
+    let file = std::env::temp_dir().join(format!("registry-state-{}.json", std::process::id()));
+    let file = file.to_str().unwrap().to_string();
+    save_state(&protobufs, &file).unwrap();
+    let loaded = load_state(&file).unwrap();
+    let _ = fs::remove_file(&file);
+
+    assert!(find_protobuf(&loaded, "dev", "proto1".to_string()).is_some(), "namespace is not persisted");
+    let p = find_protobuf(&loaded, "", "proto1".to_string()).unwrap().lock().unwrap();
+    let svc = p.services[0].lock().unwrap();
+    assert_eq!(svc.url, "url1");
+    assert_eq!(svc.id, id, "instance id is not persisted");
+    assert_eq!(svc.stk, Some(crate::common::secret_digest("tok")));
+    assert_eq!(svc.ctr, 7);
+    assert_eq!(svc.hnd, 0, "hand-outs are not persisted");
+    assert_eq!(svc.hst, HealthEnum::DRAINING);
+    assert!(svc.cdn, "cordon is not persisted");
+    assert_eq!(unix_secs(svc.rgt), registered);
+
+    let missing = load_state("no-such-registry-state.json").unwrap();
+    assert!(missing.namespaces.is_empty());
+
+    // older state files hold the tokens and instance keys themselves
+    let old = r#"{"protobufs": [{"name": "proto1", "cltk": "ctok", "services": [
+        {"url": "url1", "stk": "tok", "ctr": 0, "meta": {}, "hst": 0, "ikey": "k1"}]}]}"#;
+    let loaded = restore(serde_json::from_str(old).unwrap());
+    let p = find_protobuf(&loaded, "", "proto1".to_string()).unwrap().lock().unwrap();
+    assert_eq!(p.cltk, Some(crate::common::secret_digest("ctok")));
+    let svc = p.services[0].lock().unwrap();
+    assert_eq!((svc.stk.clone(), svc.ikey.clone()),
+               (Some(crate::common::secret_digest("tok")), crate::common::secret_digest("k1")));
+}
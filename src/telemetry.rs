@@ -0,0 +1,99 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// OpenTelemetry span export. When otlp_endpoint is set in setting.toml the
+// RPC spans are exported through OTLP/gRPC to that collector, as children of
+// the trace context extracted from the incoming request.
+
+use std::collections::HashMap;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+
+// Build the tracer provider for the configured collector. Returns None when
+// export is not configured.
+pub fn init_tracer(params: &HashMap<String, String>) -> Result<Option<TracerProvider>, String> {
+    let endpoint = match params.get("otlp_endpoint") {
+        Some(e) => e.clone(),
+        None => return Ok(None),
+    };
+    let service_name = params.get("otlp_service_name").cloned().unwrap_or("reg-server".to_string());
+    make_provider(endpoint, service_name).map(Some)
+}
+
+fn make_provider(endpoint: String, service_name: String) -> Result<TracerProvider, String> {
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(endpoint)
+        .build();
+    match exporter {
+        Ok(exporter) => Ok(TracerProvider::builder()
+            .with_batch_exporter(exporter, runtime::Tokio)
+            .with_resource(Resource::new(vec![KeyValue::new("service.name", service_name)]))
+            .build()),
+        Err(e) => Err(format!("failed to create OTLP exporter: {}", e)),
+    }
+}
+
+// Flush the spans still buffered and stop the exporter.
+pub fn shutdown(provider: Option<TracerProvider>) {
+    if let Some(Err(e)) = provider.map(|p| p.shutdown()) {
+        tracing::warn!(error = %e, "failed to flush spans");
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_export_to_local_collector() {
+    use opentelemetry::trace::{Tracer, TracerProvider as _};
+    use opentelemetry_proto::tonic::collector::trace::v1::trace_service_server::{TraceService, TraceServiceServer};
+    use opentelemetry_proto::tonic::collector::trace::v1::{ExportTraceServiceRequest, ExportTraceServiceResponse};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    // local stand-in for an OTLP collector, forwarding the spans it receives
+    struct Collector(mpsc::UnboundedSender<String>);
+
+    #[tonic::async_trait]
+    impl TraceService for Collector {
+        async fn export(&self, request: tonic::Request<ExportTraceServiceRequest>)
+            -> Result<tonic::Response<ExportTraceServiceResponse>, tonic::Status> {
+            for rs in request.into_inner().resource_spans {
+                for ss in rs.scope_spans {
+                    for span in ss.spans {
+                        let _ = self.0.send(span.name);
+                    }
+                }
+            }
+            Ok(tonic::Response::new(ExportTraceServiceResponse { partial_success: None }))
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(tonic::transport::Server::builder()
+        .add_service(TraceServiceServer::new(Collector(tx)))
+        .serve_with_incoming(TcpListenerStream::new(listener)));
+
+    let provider = make_provider(format!("http://{}", addr), "reg-server-test".to_string()).unwrap();
+    provider.tracer("test").in_span("find", |_| {});
+    tokio::task::spawn_blocking(move || provider.shutdown()).await.unwrap().unwrap();
+
+    let name = tokio::time::timeout(std::time::Duration::from_secs(5), rx.recv()).await;
+    assert_eq!(name.unwrap().unwrap(), "find");
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright 2025 Habermaas Systems, Inc. All rights reserved.
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *     *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ */
+
+// HTTP admin endpoint. GET /report returns the provider report in the format
+// given by ?format= (json, yaml, csv or table). The registry token goes in an
+// "Authorization: Bearer" header and the REPORT filters are query parameters:
+// namespace, name_prefix, label=key=value and health=SERVING, both
+// repeatable, page_size and page_token. Bad parameters answer 400, a token
+// that does not validate 401 and one not good in the namespace 403.
+// Configured by admin_address in setting.toml.
+
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use crate::common;
+use crate::export::{parse_health, parse_label, render_report, ReportFormat};
+use crate::registry::ProviderReportRequest;
+use crate::limits;
+use crate::reports;
+
+// Build the report request from the query parameters.
+fn report_request(token: String, params: &[(String, String)]) -> Result<(ProviderReportRequest, ReportFormat), String> {
+    let mut req = ProviderReportRequest {
+        token,
+        ..Default::default()
+    };
+    let mut format = ReportFormat::JSON;
+    for (key, value) in params {
+        match key.as_str() {
+            "format" => {
+                format = ReportFormat::from_name(value)
+                    .ok_or(format!("unknown format '{}'", value))?;
+            },
+            "name_prefix" => req.name_prefix = value.clone(),
+            "label" => {
+                let (k, v) = parse_label(value)?;
+                req.labels.insert(k, v);
+            },
+            "health" => req.health.push(parse_health(value)?),
+            "page_size" => {
+                req.page_size = value.parse().map_err(|_| format!("invalid page_size '{}'", value))?;
+            },
+            "page_token" => {
+                // page tokens are protobuf names returned by an earlier page
+                if value.chars().any(|c| c.is_control() || c.is_whitespace()) {
+                    return Err(format!("invalid page_token '{}'", value.escape_debug()));
+                }
+                req.page_token = value.clone();
+            },
+            "namespace" => req.namespace = common::check_namespace(value)?,
+            _ => return Err(format!("unknown parameter '{}'", key)),
+        }
+    }
+    Ok((req, format))
+}
+
+async fn report_handler(headers: HeaderMap, Query(params): Query<Vec<(String, String)>>) -> Response {
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("")
+        .to_string();
+    let (req, format) = match report_request(token, &params) {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::BAD_REQUEST, e + "\n").into_response(),
+    };
+    let rsp = match limits::subject_limited(|| reports::handle_provider_report(req)) {
+        Ok(r) => r,
+        Err(e) => return (StatusCode::TOO_MANY_REQUESTS, e + "\n").into_response(),
+    };
+    if let Some(s) = &rsp.status {
+        return (http_status(s.code), format!("{}\n", s.error_message)).into_response();
+    }
+    match render_report(&rsp, format) {
+        Ok(body) => ([(header::CONTENT_TYPE, format.content_type())], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e + "\n").into_response(),
+    }
+}
+
+// The HTTP status answering a report that failed with a registry status code.
+fn http_status(code: i32) -> StatusCode {
+    match code {
+        c if c == common::StatusEnum::AUTHERROR as i32 => StatusCode::UNAUTHORIZED,
+        c if c == common::StatusEnum::BADTOKEN as i32 => StatusCode::FORBIDDEN,
+        c if c == common::StatusEnum::NOTFOUND as i32 => StatusCode::NOT_FOUND,
+        c if c == common::StatusEnum::EXHAUSTED as i32 => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// Serve the admin endpoint on the configured address until the process exits.
+pub async fn serve(addr: String) {
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            tracing::error!(address = %addr, error = %e, "failed to start admin listener");
+            return;
+        },
+    };
+    let app = Router::new().route("/report", get(report_handler));
+    if let Err(e) = axum::serve(listener, app).await {
+        tracing::error!(error = %e, "admin listener stopped");
+    }
+}
+
+#[test]
+fn test_report_request_from_query() {
+    let params = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    };
+    let (req, format) = report_request("tok".to_string(), &params(&[
+        ("format", "csv"), ("name_prefix", "pay"), ("label", "zone=east"),
+        ("health", "serving"), ("health", "DRAINING"), ("page_size", "10"), ("namespace", "dev")])).unwrap();
+    assert_eq!(format, ReportFormat::CSV);
+    assert_eq!(req.token, "tok");
+    assert_eq!(req.name_prefix, "pay");
+    assert_eq!(req.labels.get("zone").unwrap(), "east");
+    assert_eq!(req.health, vec![crate::registry::HealthState::Serving as i32,
+                                crate::registry::HealthState::Draining as i32]);
+    assert_eq!(req.page_size, 10);
+    assert_eq!(req.namespace, "dev");
+    assert!(report_request("".to_string(), &params(&[("format", "xml")])).is_err());
+    assert!(report_request("".to_string(), &params(&[("label", "zone")])).is_err());
+    assert!(report_request("".to_string(), &params(&[("health", "sick")])).is_err());
+    assert!(report_request("".to_string(), &params(&[("page_token", "pay ments")])).is_err());
+    assert!(report_request("".to_string(), &params(&[("namespace", "dev/prod")])).is_err());
+}
+
+#[test]
+fn test_http_status() {
+    assert_eq!(http_status(common::StatusEnum::AUTHERROR as i32), StatusCode::UNAUTHORIZED);
+    assert_eq!(http_status(common::StatusEnum::BADTOKEN as i32), StatusCode::FORBIDDEN);
+    assert_eq!(http_status(common::StatusEnum::NOTFOUND as i32), StatusCode::NOT_FOUND);
+    assert_eq!(http_status(common::StatusEnum::SERVERROR as i32), StatusCode::INTERNAL_SERVER_ERROR);
+}